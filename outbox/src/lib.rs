@@ -0,0 +1,451 @@
+//! Disk-backed store-and-forward queue for reports to the center.
+//!
+//! Every report is written to `<dir>/<queue>/<seq>.json` before it is sent, and only removed
+//! once the center accepted it. A single drain task per queue hands the entries in order to a
+//! `Transport`, retrying with backoff while the backhaul is down, so nothing is lost across
+//! outages or restarts. The queue is bounded, the oldest entries are dropped first when it is full.
+//!
+//! ```ignore
+//! let outbox = Outbox::open("vehicle_status", OutboxConfig::default())?;
+//! tokio::spawn({
+//!     let outbox = outbox.clone();
+//!     async move { outbox.run(&transport, std::future::pending(), |_, _, _| {}).await }
+//! });
+//! outbox.push("vehicle/status/", &body)?;
+//! ```
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Notify;
+
+pub const DEFAULT_DIR: &str = "./data/outbox";
+pub const DEFAULT_MAX_ENTRIES: usize = 10000;
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const ENTRY_SUFFIX: &str = ".json";
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    // spool dir shared by all queues, each queue has a sub dir
+    pub dir: String,
+    pub max_entries: usize,
+    pub max_bytes: u64,
+    // longest wait between two attempts while the center is unreachable
+    pub max_backoff: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            dir: String::from(DEFAULT_DIR),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    // where the transport delivers the body, queues written before the uplink stored full urls
+    #[serde(alias = "url")]
+    topic: String,
+    body: Value,
+    // unix timestamp in seconds
+    queued_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    // sequence number and size of the entries on disk, oldest first
+    entries: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next_seq: u64,
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    name: String,
+    dir: PathBuf,
+    cfg: OutboxConfig,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+// A persistent queue, cheap to clone into the producer and the drain task
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+// Size of a queue, read from its dir
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueStats {
+    pub name: String,
+    pub depth: usize,
+    pub bytes: u64,
+}
+
+pub enum Delivery {
+    Delivered,
+    // the center refused the entry, sending it again will not help
+    Rejected(String),
+    Retry(String),
+}
+
+pub type DeliveryFuture<'a> = Pin<Box<dyn Future<Output = Delivery> + Send + 'a>>;
+
+// Delivers the queued entries to the center, e.g. over HTTP or MQTT
+pub trait Transport: Send + Sync {
+    fn deliver<'a>(&'a self, topic: &'a str, body: &'a Value) -> DeliveryFuture<'a>;
+}
+
+impl Outbox {
+    // Open the queue `name`, entries left by a previous run are sent first.
+    // `name` is a single dir below the spool dir.
+    pub fn open(name: &str, cfg: OutboxConfig) -> Result<Outbox, String> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\\' || c == '\0') {
+            return Err(format!("invalid outbox queue name {:?}", name))
+        }
+        let dir = Path::new(&cfg.dir).join(name);
+        fs::create_dir_all(&dir).map_err(|e| format!("create outbox dir {} failed: {:?}", dir.display(), e))?;
+
+        let mut state = QueueState::default();
+        let mut entries: Vec<(u64, u64)> = vec![];
+        for item in fs::read_dir(&dir).map_err(|e| format!("read outbox dir {} failed: {:?}", dir.display(), e))? {
+            let item = item.map_err(|e| format!("read outbox dir {} failed: {:?}", dir.display(), e))?;
+            let file_name = item.file_name().to_string_lossy().into_owned();
+            match file_name.strip_suffix(ENTRY_SUFFIX).and_then(|seq| seq.parse::<u64>().ok()) {
+                Some(seq) => entries.push((seq, item.metadata().map(|m| m.len()).unwrap_or(0))),
+                // half written entries of a crash
+                None => {
+                    let _ = fs::remove_file(item.path());
+                },
+            }
+        }
+        entries.sort_unstable();
+        for (seq, size) in entries {
+            state.bytes += size;
+            state.next_seq = seq + 1;
+            state.entries.push_back((seq, size));
+        }
+        if !state.entries.is_empty() {
+            info!("outbox[{}] has {} entries from a previous run", name, state.entries.len());
+        }
+
+        Ok(Outbox {inner: Arc::new(Inner {
+            name: String::from(name),
+            dir,
+            cfg,
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        })})
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    // Queue `body` to be delivered under `topic`, it is on disk when this returns, even if the power fails
+    pub fn push(&self, topic: &str, body: &Value) -> Result<(), String> {
+        let entry = Entry {topic: String::from(topic), body: body.clone(), queued_at: unix_now()};
+        let data = serde_json::to_vec(&entry).map_err(|e| format!("encode outbox entry failed: {:?}", e))?;
+        let size = data.len() as u64;
+
+        let mut state = self.inner.state.lock().map_err(|e| format!("lock outbox failed: {:?}", e))?;
+        let seq = state.next_seq;
+        let path = self.entry_path(seq);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path).map_err(|e| format!("write outbox entry {} failed: {:?}", tmp_path.display(), e))?;
+        file.write_all(&data).and_then(|_| file.sync_all())
+            .map_err(|e| format!("write outbox entry {} failed: {:?}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| format!("write outbox entry {} failed: {:?}", path.display(), e))?;
+        // the rename is only durable once the dir is synced
+        File::open(&self.inner.dir).and_then(|dir| dir.sync_all())
+            .map_err(|e| format!("sync outbox dir {} failed: {:?}", self.inner.dir.display(), e))?;
+        state.next_seq += 1;
+        state.entries.push_back((seq, size));
+        state.bytes += size;
+
+        // keep the newest data when the backhaul has been down for too long
+        while state.entries.len() > self.inner.cfg.max_entries || state.bytes > self.inner.cfg.max_bytes {
+            let (old_seq, old_size) = match state.entries.pop_front() {
+                Some(e) => e,
+                None => break,
+            };
+            let _ = fs::remove_file(self.entry_path(old_seq));
+            state.bytes -= old_size;
+            state.dropped += 1;
+            if state.dropped == 1 || state.dropped % 100 == 0 {
+                error!("outbox[{}] is full, dropped {} entries so far", self.inner.name, state.dropped);
+            }
+        }
+        drop(state);
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    pub fn depth(&self) -> usize {
+        self.inner.state.lock().map(|s| s.entries.len()).unwrap_or(0)
+    }
+
+    // entries dropped because the queue was full, since it was opened
+    pub fn dropped(&self) -> u64 {
+        self.inner.state.lock().map(|s| s.dropped).unwrap_or(0)
+    }
+
+    // Deliver the queued entries in order over `transport` until `stopped` resolves,
+    // `observe` gets the topic, outcome and latency of every attempt
+    pub async fn run<T, F, O>(&self, transport: &T, stopped: F, mut observe: O)
+    where
+        T: Transport + ?Sized,
+        F: Future<Output = ()>,
+        O: FnMut(&str, &Delivery, Duration),
+    {
+        tokio::pin!(stopped);
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let seq = match self.front() {
+                Some(seq) => seq,
+                None => {
+                    tokio::select! {
+                        _ = self.inner.notify.notified() => continue,
+                        _ = &mut stopped => return,
+                    }
+                }
+            };
+            let entry = match self.read_entry(seq) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("outbox[{}] drop unreadable entry {}: {}", self.inner.name, seq, e);
+                    self.remove(seq);
+                    continue;
+                }
+            };
+
+            let sent_at = Instant::now();
+            let delivery = tokio::select! {
+                d = transport.deliver(&entry.topic, &entry.body) => d,
+                _ = &mut stopped => return,
+            };
+            observe(&entry.topic, &delivery, sent_at.elapsed());
+            match delivery {
+                Delivery::Delivered => {
+                    debug!("outbox[{}] entry {} delivered to {}", self.inner.name, seq, entry.topic);
+                    if backoff > INITIAL_BACKOFF {
+                        info!("outbox[{}] center is reachable again, {} entries left", self.inner.name, self.depth().saturating_sub(1));
+                    }
+                    self.remove(seq);
+                    backoff = INITIAL_BACKOFF;
+                },
+                Delivery::Rejected(reason) => {
+                    error!("outbox[{}] entry {} for {} rejected, dropped: {}", self.inner.name, seq, entry.topic, reason);
+                    self.remove(seq);
+                },
+                Delivery::Retry(reason) => {
+                    warn!("outbox[{}] deliver {} failed, retry in {:?}, {} entries queued: {}",
+                          self.inner.name, entry.topic, backoff, self.depth(), reason);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {},
+                        _ = &mut stopped => return,
+                    }
+                    backoff = std::cmp::min(backoff * 2, self.inner.cfg.max_backoff);
+                },
+            }
+        }
+    }
+
+    fn front(&self) -> Option<u64> {
+        self.inner.state.lock().ok().and_then(|s| s.entries.front().map(|(seq, _)| *seq))
+    }
+
+    fn read_entry(&self, seq: u64) -> Result<Entry, String> {
+        let data = fs::read(self.entry_path(seq)).map_err(|e| format!("read failed: {:?}", e))?;
+        serde_json::from_slice(&data).map_err(|e| format!("decode failed: {:?}", e))
+    }
+
+    // the entry may already be gone when the queue overflowed while it was sent
+    fn remove(&self, seq: u64) {
+        if let Ok(mut state) = self.inner.state.lock() {
+            if let Some(pos) = state.entries.iter().position(|(s, _)| *s == seq) {
+                if let Some((_, size)) = state.entries.remove(pos) {
+                    state.bytes -= size;
+                }
+                let _ = fs::remove_file(self.entry_path(seq));
+            }
+        }
+    }
+
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        // zero padded, so the file names sort like the sequence numbers
+        self.inner.dir.join(format!("{:020}{}", seq, ENTRY_SUFFIX))
+    }
+}
+
+// Depth and size of every queue under the spool dir `dir`
+pub fn queue_stats(dir: &str) -> Vec<QueueStats> {
+    let mut stats: Vec<QueueStats> = vec![];
+    let queues = match fs::read_dir(dir) {
+        Ok(q) => q,
+        Err(_) => return stats,
+    };
+    for queue in queues.filter_map(|q| q.ok()) {
+        if !queue.path().is_dir() {
+            continue;
+        }
+        let mut depth = 0;
+        let mut bytes = 0;
+        if let Ok(items) = fs::read_dir(queue.path()) {
+            for item in items.filter_map(|i| i.ok()) {
+                if item.file_name().to_string_lossy().ends_with(ENTRY_SUFFIX) {
+                    depth += 1;
+                    bytes += item.metadata().map(|m| m.len()).unwrap_or(0);
+                }
+            }
+        }
+        stats.push(QueueStats {name: queue.file_name().to_string_lossy().into_owned(), depth, bytes});
+    }
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Delivers every entry and records the topics in the order they arrived
+    #[derive(Default)]
+    struct Recorder {
+        topics: Mutex<Vec<String>>,
+        reject: Option<String>,
+    }
+
+    impl Transport for Recorder {
+        fn deliver<'a>(&'a self, topic: &'a str, _body: &'a Value) -> DeliveryFuture<'a> {
+            Box::pin(async move {
+                self.topics.lock().unwrap().push(String::from(topic));
+                match &self.reject {
+                    Some(rejected) if rejected == topic => Delivery::Rejected(String::from("refused")),
+                    _ => Delivery::Delivered,
+                }
+            })
+        }
+    }
+
+    fn config(name: &str) -> OutboxConfig {
+        let dir = std::env::temp_dir().join(format!("rsu-outbox-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        OutboxConfig {dir: dir.to_string_lossy().into_owned(), ..OutboxConfig::default()}
+    }
+
+    async fn drain(outbox: &Outbox, transport: &Recorder) {
+        let emptied = async {
+            while outbox.depth() > 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        outbox.run(transport, emptied, |_, _, _| {}).await;
+    }
+
+    #[test]
+    fn queue_names_stay_below_the_spool_dir() {
+        let cfg = config("names");
+        for name in &["", ".", "..", "../status", "a/b", "a\\b"] {
+            assert!(Outbox::open(name, cfg.clone()).is_err(), "{:?} was accepted", name);
+        }
+        assert!(!Path::new(&cfg.dir).exists());
+    }
+
+    #[test]
+    fn entries_survive_a_restart() {
+        let cfg = config("restart");
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        outbox.push("a", &Value::from(1)).unwrap();
+        outbox.push("b", &Value::from(2)).unwrap();
+        drop(outbox);
+        // a crash while an entry was written leaves a tmp file behind
+        fs::write(Path::new(&cfg.dir).join("status").join("00000000000000000002.tmp"), b"{").unwrap();
+
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        assert_eq!(outbox.depth(), 2);
+        assert_eq!(outbox.read_entry(0).unwrap().topic, "a");
+        // new entries are queued after the old ones
+        outbox.push("c", &Value::from(3)).unwrap();
+        assert_eq!(outbox.front(), Some(0));
+        assert!(outbox.entry_path(2).exists());
+        let stats = queue_stats(&cfg.dir);
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].name.as_str(), stats[0].depth), ("status", 3));
+        let _ = fs::remove_dir_all(&cfg.dir);
+    }
+
+    #[tokio::test]
+    async fn entries_are_replayed_in_order() {
+        let cfg = config("replay");
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        for topic in &["a", "b", "c"] {
+            outbox.push(topic, &Value::Null).unwrap();
+        }
+        drop(outbox);
+
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        outbox.push("d", &Value::Null).unwrap();
+        let transport = Recorder {reject: Some(String::from("b")), ..Recorder::default()};
+        drain(&outbox, &transport).await;
+        // a rejected entry is dropped, not sent again
+        assert_eq!(*transport.topics.lock().unwrap(), vec!["a", "b", "c", "d"]);
+        assert_eq!(queue_stats(&cfg.dir)[0].depth, 0);
+        let _ = fs::remove_dir_all(&cfg.dir);
+    }
+
+    #[tokio::test]
+    async fn oldest_entries_are_dropped_at_max_entries() {
+        let cfg = OutboxConfig {max_entries: 2, ..config("max-entries")};
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        for topic in &["a", "b", "c"] {
+            outbox.push(topic, &Value::Null).unwrap();
+        }
+        assert_eq!(outbox.depth(), 2);
+        assert_eq!(outbox.dropped(), 1);
+        assert!(!outbox.entry_path(0).exists());
+
+        let transport = Recorder::default();
+        drain(&outbox, &transport).await;
+        assert_eq!(*transport.topics.lock().unwrap(), vec!["b", "c"]);
+        let _ = fs::remove_dir_all(&cfg.dir);
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped_at_max_bytes() {
+        let cfg = config("max-bytes");
+        let outbox = Outbox::open("probe", cfg.clone()).unwrap();
+        outbox.push("a", &Value::Null).unwrap();
+        let size = queue_stats(&cfg.dir)[0].bytes;
+
+        // room for two entries of the same size
+        let cfg = OutboxConfig {max_bytes: size * 2, ..config("max-bytes")};
+        let outbox = Outbox::open("status", cfg.clone()).unwrap();
+        for topic in &["a", "b", "c", "d"] {
+            outbox.push(topic, &Value::Null).unwrap();
+        }
+        assert_eq!(outbox.depth(), 2);
+        assert_eq!(outbox.dropped(), 2);
+        assert_eq!(outbox.read_entry(outbox.front().unwrap()).unwrap().topic, "c");
+        assert_eq!(queue_stats(&cfg.dir)[0].bytes, size * 2);
+        let _ = fs::remove_dir_all(&cfg.dir);
+    }
+}
@@ -0,0 +1,178 @@
+//! C ABI shared by the RSU host and plugin libraries.
+//!
+//! Only `#[repr(C)]` types and `extern "C"` functions cross the boundary, so a
+//! plugin does not have to be built by the same compiler as the host. A plugin
+//! exports two symbols:
+//!
+//! * `rsu_plugin_abi_version` - returns the ABI version the plugin was built against,
+//!   the host checks it before touching anything else in the library
+//! * `rsu_plugin_entry` - returns a pointer to the plugin's static `PluginEntry`
+//!
+//! `RSU_PLUGIN_ABI_VERSION` must be bumped whenever a type or constant in this crate changes layout or meaning.
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const RSU_PLUGIN_ABI_VERSION: u32 = 5;
+
+// `run` return values, negative values are failures
+pub const RUN_OK: i32 = 0;
+
+pub const ABI_VERSION_SYMBOL: &[u8] = b"rsu_plugin_abi_version\0";
+pub const ENTRY_SYMBOL: &[u8] = b"rsu_plugin_entry\0";
+
+pub type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub type EntryFn = unsafe extern "C" fn() -> *const PluginEntry;
+pub type RunFn = unsafe extern "C" fn(host: *const HostContext) -> i32;
+// `topic` and `payload` are utf-8 and not nul terminated, `payload` is a json document
+pub type ReportFn = unsafe extern "C" fn(host_data: *const c_void, topic: *const u8, topic_len: usize,
+                                         payload: *const u8, payload_len: usize) -> i32;
+
+// `report` return values, negative values are failures
+pub const REPORT_OK: i32 = 0;
+
+// Flags owned by the host and shared with a running plugin.
+// `running` is cleared by the host to ask the plugin to stop,
+// `error` is set by the plugin when it can not keep running.
+// `keep_loaded` is set by a plugin whose code may still run on threads it does not own
+// (e.g. a global executor) after `run` returns, the host must then never unload the library,
+// whatever `run` returned.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PluginFlags {
+    running: AtomicBool,
+    error: AtomicBool,
+    keep_loaded: AtomicBool,
+}
+
+impl PluginFlags {
+    pub fn new() -> PluginFlags {
+        PluginFlags {
+            running: AtomicBool::new(true),
+            error: AtomicBool::new(false),
+            keep_loaded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.error.load(Ordering::SeqCst)
+    }
+
+    pub fn set_error(&self) {
+        self.error.store(true, Ordering::SeqCst);
+    }
+
+    pub fn keep_loaded(&self) -> bool {
+        self.keep_loaded.load(Ordering::SeqCst)
+    }
+
+    pub fn set_keep_loaded(&self) {
+        self.keep_loaded.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Default for PluginFlags {
+    fn default() -> Self {
+        PluginFlags::new()
+    }
+}
+
+// Everything the host hands to `run`, valid until `run` returns
+#[repr(C)]
+pub struct HostContext {
+    pub flags: *const PluginFlags,
+    // yaml config document of the plugin, utf-8 and not nul terminated, empty for the plugin defaults
+    pub config: *const u8,
+    pub config_len: usize,
+    // hands a report to the host uplink, which delivers it to the center,
+    // returns REPORT_OK once the report is queued
+    pub report: ReportFn,
+    // passed back to `report` as is
+    pub host_data: *const c_void,
+}
+
+impl HostContext {
+    /// # Safety
+    /// `config` must point to `config_len` valid bytes
+    pub unsafe fn config(&self) -> Option<&str> {
+        if self.config.is_null() {
+            return Some("")
+        }
+        std::str::from_utf8(std::slice::from_raw_parts(self.config, self.config_len)).ok()
+    }
+}
+
+// Entry table exported by a plugin, must live for the lifetime of the library
+#[repr(C)]
+pub struct PluginEntry {
+    pub abi_version: u32,
+    // nul terminated plugin name
+    pub name: *const c_char,
+    // blocks until `running` is cleared and every resource of the plugin is released,
+    // returns RUN_OK or a negative value on failure
+    pub run: RunFn,
+}
+
+// The entry only holds a pointer to a static string and a function pointer
+unsafe impl Sync for PluginEntry {}
+
+// Plugin side handle to the host owned `PluginFlags`.
+// The host keeps the flags alive until `run` has returned.
+#[derive(Debug, Clone, Copy)]
+pub struct FlagsRef(*const PluginFlags);
+
+unsafe impl Send for FlagsRef {}
+unsafe impl Sync for FlagsRef {}
+
+impl FlagsRef {
+    /// # Safety
+    /// `flags` must come from the `HostContext` passed to `run` by the host
+    pub unsafe fn from_raw(flags: *const PluginFlags) -> Option<FlagsRef> {
+        if flags.is_null() {
+            None
+        } else {
+            Some(FlagsRef(flags))
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        unsafe { (*self.0).is_running() }
+    }
+
+    pub fn set_error(&self) {
+        unsafe { (*self.0).set_error() }
+    }
+
+    pub fn set_keep_loaded(&self) {
+        unsafe { (*self.0).set_keep_loaded() }
+    }
+}
+
+// Plugin side handle to the host uplink, valid until `run` returns
+#[derive(Debug, Clone, Copy)]
+pub struct ReporterRef {
+    report: ReportFn,
+    host_data: *const c_void,
+}
+
+unsafe impl Send for ReporterRef {}
+unsafe impl Sync for ReporterRef {}
+
+impl ReporterRef {
+    /// # Safety
+    /// `host` must be the `HostContext` passed to `run` by the host
+    pub unsafe fn from_host(host: &HostContext) -> ReporterRef {
+        ReporterRef {report: host.report, host_data: host.host_data}
+    }
+
+    pub fn report(&self, topic: &str, payload: &str) -> i32 {
+        unsafe { (self.report)(self.host_data, topic.as_ptr(), topic.len(), payload.as_ptr(), payload.len()) }
+    }
+}
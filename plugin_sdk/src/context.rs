@@ -0,0 +1,104 @@
+use log::error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use serde::Serialize;
+use rsu_plugin_abi::{FlagsRef, ReporterRef, REPORT_OK};
+
+// Handle given to a running plugin, cheap to clone into the plugin's tasks
+#[derive(Debug, Clone)]
+pub struct PluginContext {
+    name: &'static str,
+    flags: FlagsRef,
+    reporter: ReporterRef,
+    failure: Arc<Mutex<Option<String>>>,
+    shutdown: watch::Receiver<bool>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    ports: Arc<Mutex<Vec<u16>>>,
+}
+
+impl PluginContext {
+    pub(crate) fn new(name: &'static str, flags: FlagsRef, reporter: ReporterRef, shutdown: watch::Receiver<bool>) -> PluginContext {
+        PluginContext {
+            name,
+            flags,
+            reporter,
+            failure: Arc::new(Mutex::new(None)),
+            shutdown,
+            tasks: Arc::new(Mutex::new(vec![])),
+            ports: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.flags.is_running()
+    }
+
+    // report that the plugin can not keep running, the host will stop it
+    pub fn fail(&self, reason: &str) {
+        error!("plugin[{}] failed: {}", self.name, reason);
+        if let Ok(mut failure) = self.failure.lock() {
+            if failure.is_none() {
+                *failure = Some(String::from(reason));
+            }
+        }
+        self.flags.set_error();
+    }
+
+    // Hand `body` to the host for delivery to the center under `topic`, e.g. `vehicle/status/`.
+    // The report is on disk in the host queue when this returns, it is delivered in order once the uplink is up.
+    pub fn report<T: Serialize>(&self, topic: &str, body: &T) -> Result<(), String> {
+        let payload = serde_json::to_string(body).map_err(|e| format!("serialize report failed: {:?}", e))?;
+        match self.reporter.report(topic, &payload) {
+            REPORT_OK => Ok(()),
+            ret => Err(format!("host refused report for {}: {}", topic, ret)),
+        }
+    }
+
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().ok().and_then(|f| f.clone())
+    }
+
+    // Spawn a task owned by the plugin. On stop the task has to return once `stopped`
+    // resolves, tasks still running after the teardown timeout are aborted.
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(task);
+        match self.tasks.lock() {
+            Ok(mut tasks) => tasks.push(handle),
+            Err(e) => error!("plugin[{}] track task failed: {:?}", self.name, e),
+        }
+    }
+
+    // resolves once the host asked the plugin to stop
+    pub async fn stopped(&self) {
+        let mut shutdown = self.shutdown.clone();
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                return
+            }
+        }
+    }
+
+    // a port the plugin listens on, checked to be free again after teardown
+    pub fn register_port(&self, port: u16) {
+        if let Ok(mut ports) = self.ports.lock() {
+            ports.push(port);
+        }
+    }
+
+    pub(crate) fn take_tasks(&self) -> Vec<JoinHandle<()>> {
+        self.tasks.lock().map(|mut tasks| tasks.drain(..).collect()).unwrap_or_default()
+    }
+
+    pub(crate) fn ports(&self) -> Vec<u16> {
+        self.ports.lock().map(|ports| ports.clone()).unwrap_or_default()
+    }
+}
@@ -0,0 +1,88 @@
+//! SDK for writing RSU plugins.
+//!
+//! A plugin implements `RsuPlugin` and calls `declare_plugin!` once, the macro
+//! generates the symbols the host resolves through `rsu-plugin-abi`.
+//!
+//! Teardown contract: every task is spawned with `PluginContext::spawn` and returns once
+//! `PluginContext::stopped` resolves, closing its listeners and zenoh sessions on the way out.
+//! The host unloads the library as soon as `run` returns.
+//!
+//! Reports for the center are handed to the host with `PluginContext::report`, the host queues
+//! them on disk and delivers them over the uplink configured in rsu.yaml.
+//!
+//! ```ignore
+//! use rsu_plugin_sdk::{declare_plugin, PluginContext, RsuPlugin};
+//!
+//! #[derive(Default)]
+//! struct Demo;
+//!
+//! impl RsuPlugin for Demo {
+//!     fn config(&mut self, _doc: &str) -> Result<(), String> { Ok(()) }
+//!     fn start(&mut self, _ctx: &PluginContext) -> Result<(), String> { Ok(()) }
+//!     fn stop(&mut self) -> Result<(), String> { Ok(()) }
+//! }
+//!
+//! declare_plugin!("demo", Demo::default);
+//! ```
+pub use rsu_plugin_abi as abi;
+
+mod context;
+mod runtime;
+pub use context::PluginContext;
+pub use runtime::run_plugin;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Health {
+    Healthy,
+    Unhealthy(String),
+}
+
+pub trait RsuPlugin: Send + 'static {
+    // parse the yaml config document supplied by the host, called once before start,
+    // an empty document means the plugin runs with its defaults
+    fn config(&mut self, doc: &str) -> Result<(), String>;
+
+    // called inside the plugin runtime, spawn the plugin tasks and return without blocking
+    fn start(&mut self, ctx: &PluginContext) -> Result<(), String>;
+
+    // called inside the plugin runtime after the host asked the plugin to stop,
+    // tasks spawned with `PluginContext::spawn` are awaited after it returns
+    fn stop(&mut self) -> Result<(), String>;
+
+    // polled once a tick while running, an unhealthy plugin is reported to the host
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    // false if code of the plugin can outlive its runtime, e.g. on the async-std global executor,
+    // the host then keeps the library loaded after stop, even when start or stop failed
+    fn unload_safe(&self) -> bool {
+        true
+    }
+}
+
+#[macro_export]
+macro_rules! declare_plugin {
+    ($name:literal, $constructor:path) => {
+        extern "C" fn __rsu_plugin_run(host: *const $crate::abi::HostContext) -> i32 {
+            // the host passes the context it keeps alive until `run` returns
+            unsafe { $crate::run_plugin($name, host, $constructor) }
+        }
+
+        static __RSU_PLUGIN_ENTRY: $crate::abi::PluginEntry = $crate::abi::PluginEntry {
+            abi_version: $crate::abi::RSU_PLUGIN_ABI_VERSION,
+            name: concat!($name, "\0").as_ptr() as *const ::std::os::raw::c_char,
+            run: __rsu_plugin_run,
+        };
+
+        #[no_mangle]
+        pub extern "C" fn rsu_plugin_abi_version() -> u32 {
+            $crate::abi::RSU_PLUGIN_ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn rsu_plugin_entry() -> *const $crate::abi::PluginEntry {
+            &__RSU_PLUGIN_ENTRY
+        }
+    };
+}
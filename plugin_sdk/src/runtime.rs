@@ -0,0 +1,144 @@
+use log::{info, error, debug};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+use rsu_plugin_abi::{FlagsRef, HostContext, ReporterRef, RUN_OK};
+use crate::{Health, PluginContext, RsuPlugin};
+
+const TICK: Duration = Duration::from_secs(1);
+// how long the plugin tasks get to return after stop
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Body of the exported `run`, generated by `declare_plugin!`.
+///
+/// # Safety
+///
+/// `host` has to be null or point to a `HostContext` that outlives the call.
+pub unsafe fn run_plugin<P: RsuPlugin>(name: &'static str, host: *const HostContext, constructor: fn() -> P) -> i32 {
+    let _ = env_logger::try_init().map_err(|e| {
+        error!("{} init env log failed: {:?}", name, e);
+    });
+
+    if host.is_null() {
+        error!("plugin[{}] started without host context", name);
+        return -1
+    }
+    let host = &*host;
+    let flags = match FlagsRef::from_raw(host.flags) {
+        Some(f) => f,
+        None => {
+            error!("plugin[{}] started without flags", name);
+            return -1
+        },
+    };
+    let reporter = ReporterRef::from_host(host);
+    let config = match host.config() {
+        Some(c) => String::from(c),
+        None => {
+            error!("plugin[{}] config is not valid utf-8", name);
+            flags.set_error();
+            return -1
+        },
+    };
+
+    // a panic must not unwind across the C ABI into the host
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| run_inner(name, flags, reporter, &config, constructor)));
+    match ret {
+        Ok(ret) => ret,
+        Err(e) => {
+            error!("plugin[{}] panicked: {:?}", name, e);
+            flags.set_error();
+            -1
+        }
+    }
+}
+
+fn run_inner<P: RsuPlugin>(name: &'static str, flags: FlagsRef, reporter: ReporterRef, config: &str, constructor: fn() -> P) -> i32 {
+    let rt = match Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            error!("plugin[{}] new runtime failed: {:?}", name, e);
+            flags.set_error();
+            return -1
+        },
+    };
+
+    let mut plugin = constructor();
+    // set before anything of the plugin runs, a failed start or teardown must not get the library unloaded
+    if !plugin.unload_safe() {
+        flags.set_keep_loaded();
+    }
+    if let Err(e) = plugin.config(config) {
+        error!("plugin[{}] read config failed: {}", name, e);
+        flags.set_error();
+        return -1
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let ctx = PluginContext::new(name, flags, reporter, shutdown_rx);
+    let mut ret = rt.block_on(async {
+        let mut ret = RUN_OK;
+        match plugin.start(&ctx) {
+            Ok(_) => {
+                info!("plugin[{}] started", name);
+                while ctx.is_running() {
+                    tokio::time::sleep(TICK).await;
+                    if let Health::Unhealthy(reason) = plugin.health() {
+                        if ctx.failure().is_none() {
+                            ctx.fail(&reason);
+                        }
+                    }
+                }
+
+                debug!("plugin[{}] stopping", name);
+                if let Err(e) = plugin.stop() {
+                    error!("plugin[{}] stop failed: {}", name, e);
+                    ret = -1;
+                }
+            },
+            Err(e) => {
+                ctx.fail(&format!("start failed: {}", e));
+                ret = -1;
+            }
+        }
+
+        let _ = shutdown_tx.send(true);
+        if !teardown_tasks(&ctx).await {
+            ret = -1;
+        }
+        ret
+    });
+
+    // nothing spawned by the plugin may outlive `run`, the host unloads the library afterwards
+    rt.shutdown_timeout(TEARDOWN_TIMEOUT);
+
+    for port in ctx.ports() {
+        match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(_) => debug!("plugin[{}] port {} released", name, port),
+            Err(e) => {
+                error!("plugin[{}] port {} is still bound after teardown: {:?}", name, port, e);
+                ret = -1;
+            }
+        }
+    }
+
+    info!("plugin[{}] stopped", name);
+    ret
+}
+
+// wait for the plugin tasks to return, abort the ones that miss the deadline
+async fn teardown_tasks(ctx: &PluginContext) -> bool {
+    let deadline = Instant::now() + TEARDOWN_TIMEOUT;
+    let mut clean = true;
+    for mut task in ctx.take_tasks() {
+        let remain = deadline.saturating_duration_since(Instant::now());
+        if tokio::time::timeout(remain, &mut task).await.is_err() {
+            error!("plugin[{}] task did not return within {:?}, abort it", ctx.name(), TEARDOWN_TIMEOUT);
+            task.abort();
+            clean = false;
+        }
+    }
+    clean
+}
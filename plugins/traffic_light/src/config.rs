@@ -2,17 +2,17 @@
 /// reader cfg
 ///
 use log::debug;
-use std::env;
 use std::error::Error;
-use std::fs;
-use std::path::Path;
 extern crate yaml_rust;
 use crate::light;
 use light::{LightColor, LightStatus, LIGHTDURATION, LIGHTGROUP, LIGHTSTATUS};
-use yaml_rust::{YamlEmitter, YamlLoader};
+use yaml_rust::YamlLoader;
 
-fn generate_cfg(cfg_path: &str) -> Result<(), Box<dyn Error>> {
-    let rsu_default = r#"---
+// reports go to the center through the rsu uplink, under this topic followed by the road id
+const DEFAULT_REPORT_TOPIC: &str = "traffic_light/status/";
+
+// used when the host supplies an empty config document
+const DEFAULT_CFG: &str = r#"---
 port: '8081'
 road_id: "34806"  # 红绿灯路口的ID，和地图中的路口对应
 light_id_group: {   # 红绿灯ID，和地图中的路口灯对应
@@ -29,41 +29,20 @@ duration:
     red: 30
     unknown: -1
 
-center_db_url: 'http://IP:PORT/rsu/rsu_id/traffic_light/status/'
+report_topic: 'traffic_light/status/'  # 上报CenterDB的主题，后接road_id
 "#;
-    let docs = YamlLoader::load_from_str(&rsu_default)?;
-    let doc = &docs[0];
-    let mut writer = String::new();
-    let mut emitter = YamlEmitter::new(&mut writer);
-    emitter.dump(doc)?;
-    fs::write(&cfg_path, writer)?;
-    Ok(())
-}
 
-pub fn read_config(file_name: &str) -> Result<(String, String, String), Box<dyn Error>> {
-    if !Path::new(file_name).exists() {
-        let mut dir_path_vec: Vec<&str> = file_name.split('/').collect();
-        dir_path_vec.pop();
-        fs::create_dir_all(dir_path_vec.join("/"))?;
-        fs::File::create(file_name)?;
-        generate_cfg(file_name)?;
-    }
-    let config_str = fs::read_to_string(file_name)?;
-    let config_docs = YamlLoader::load_from_str(config_str.as_str())?;
-    let config = &config_docs[0];
+pub fn read_config(doc: &str) -> Result<(String, String, String), Box<dyn Error>> {
+    let config_str = if doc.trim().is_empty() { DEFAULT_CFG } else { doc };
+    let config_docs = YamlLoader::load_from_str(config_str)?;
+    let config = config_docs.get(0).ok_or("traffic light config is empty".to_owned())?;
     let light_group_cfg = &config["light_id_group"];
     let road_id = String::from(
         config["road_id"]
             .as_str()
             .ok_or("get road_id from traffic light config failed".to_owned())?,
     );
-    let ip = env::var("HOST_IP").unwrap_or("127.0.0.1".to_string());
-    let center_db_url = String::from(
-        config["center_db_url"]
-            .as_str()
-            .ok_or("get center_db_url from raffic light config tfailed".to_owned())?,
-    )
-    .replace("127.0.0.1", &ip);
+    let report_topic = String::from(config["report_topic"].as_str().unwrap_or(DEFAULT_REPORT_TOPIC));
     let port = String::from(
         config["port"]
             .as_str()
@@ -155,5 +134,5 @@ pub fn read_config(file_name: &str) -> Result<(String, String, String), Box<dyn
         }
     }
     debug!("read traffic light config ok");
-    Ok((road_id, center_db_url, port))
+    Ok((road_id, report_topic, port))
 }
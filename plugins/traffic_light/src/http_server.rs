@@ -1,13 +1,12 @@
 use log::{info, debug, error};
 use tide::{Request, Response};
 use tide::utils::{After};
-use std::sync::Arc;
-use std::sync::Mutex;
 use percent_encoding::{percent_decode};
 use serde_json::{Value, json};
 use serde::{Deserialize, Serialize};
 use crate::light;
 use light::{LightColor};
+use rsu_plugin_sdk::PluginContext;
 
 #[derive(Deserialize, Serialize)]
 struct ResponseData {
@@ -15,7 +14,14 @@ struct ResponseData {
     message: String,
 }
 
-pub async fn serve_http(port: String, error_flag: Arc<Mutex<bool>>) -> Result<(), String> {
+pub async fn serve_http(port: String, ctx: PluginContext) {
+    match port.parse::<u16>() {
+        Ok(p) => ctx.register_port(p),
+        Err(e) => {
+            ctx.fail(&format!("traffic light port {} is invalid: {:?}", port, e));
+            return
+        }
+    };
     let mut app = tide::new();
 
     app.at("/").get(|_| async { Ok("Traffic Light OK") });
@@ -86,20 +92,17 @@ pub async fn serve_http(port: String, error_flag: Arc<Mutex<bool>>) -> Result<()
     });
 
     
-    match app.listen(format!("0.0.0.0:{}", port)).await {
-        Ok(_) => {
-            info!("start traffic light server OK ......");
+    info!("start traffic light server ......");
+    // dropping the listen future closes the listener
+    tokio::select! {
+        ret = app.listen(format!("0.0.0.0:{}", port)) => {
+            if let Err(e) = ret {
+                error!("start traffic light error: {:?}", e);
+                ctx.fail(&format!("start traffic light server failed: {:?}", e));
+            }
+        },
+        _ = ctx.stopped() => {
+            info!("traffic light server stopped");
         },
-        Err(e) => {
-            error!("start traffic light error: {:?}", e);
-            let mut error_flag = error_flag.lock().map_err(|e| {
-                error!("lock traffic light error_flag failed: {:?}", e);
-                format!("lock traffic light error_flag failed: {:?}", e)
-            })?;
-
-            *error_flag = true;
-        }
-
     };
-    Ok(())
 }
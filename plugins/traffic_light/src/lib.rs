@@ -1,92 +1,56 @@
-use log::{info, error, debug};
-use std::sync::Arc;
-use std::sync::Mutex;
-use async_std::task;
-use std::time;
-use tokio;
-use tokio::runtime::Runtime;
+use log::{info, error};
+use rsu_plugin_sdk::{declare_plugin, PluginContext, RsuPlugin};
 mod config;
 use config::read_config;
 mod light;
 mod http_server;
 
 
-async fn plugin_main(error_flag: Arc<Mutex<bool>>) -> Result<i32, String> {
-    let cfg_path = "./config/plugins/traffic_light.yaml";
-
-    let (road_id, center_db_url, port) = match read_config(&cfg_path){
-        Ok((road_id, center_db_url, port)) => (road_id, center_db_url, port),
-        Err(e) => {
-            {
-                let mut error_flag = error_flag.lock().map_err(|e| {
-                    error!("lock traffic light error_flag failed: {:?}", e);
-                    format!("lock traffic light error_flag failed: {:?}", e)
-                })?;
-                *error_flag = true;
-            }
-            error!("read traffic light config failed: {:?}", e.to_string());
-            return Err(format!("read traffic light config failed: {:?}", e.to_string()))
-        },
-    };
-    
-    let error_flag_clone = Arc::clone(&error_flag);
-    tokio::spawn(http_server::serve_http(port, error_flag_clone));
-
-    match light::light_loop(road_id, center_db_url).await {
-        Ok(_) => {
-            info!("traffic light is looping...");
-        },
-        Err(e) => {
-            {
-                let mut error_flag = error_flag.lock().map_err(|e| {
-                    error!("lock traffic light error_flag failed: {:?}", e);
-                    format!("lock traffic light error_flag failed: {:?}", e)
-                })?;
-                *error_flag = true;
-            }
-            error!("traffic light loop failed: {:?}", e.to_string());
+#[derive(Default)]
+struct TrafficLight {
+    road_id: String,
+    report_topic: String,
+    port: String,
+}
 
-            return Err(format!("traffic light loop light failed: {:?}", e.to_string()))
-        }
-    };
+impl RsuPlugin for TrafficLight {
+    fn config(&mut self, doc: &str) -> Result<(), String> {
+        let (road_id, report_topic, port) = read_config(doc)
+            .map_err(|e| format!("read traffic light config failed: {:?}", e.to_string()))?;
+        self.road_id = road_id;
+        self.report_topic = report_topic;
+        self.port = port;
+        Ok(())
+    }
 
-    Ok(0)
-}
+    fn start(&mut self, ctx: &PluginContext) -> Result<(), String> {
+        ctx.spawn(http_server::serve_http(self.port.clone(), ctx.clone()));
+        ctx.spawn(light_main(self.road_id.clone(), self.report_topic.clone(), ctx.clone()));
+        Ok(())
+    }
 
-async fn async_run(running_flag: Arc<Mutex<bool>>, error_flag: Arc<Mutex<bool>>) -> i32 {
-    tokio::spawn(plugin_main(error_flag));
-    
-    loop {
-        task::sleep(time::Duration::from_secs(1)).await;
-        let the_flag = match running_flag.lock(){
-            Ok(f) => f,
-            Err(e) => {
-                debug!("lock running_flag failed: {:?}", e);
-                return -1
-            },
-        };
+    fn stop(&mut self) -> Result<(), String> {
+        info!("plugin traffic light stopped");
+        Ok(())
+    }
 
-        if !*the_flag {
-            info!("plugin traffic light stopped");
-            return 0
-            // break;
-        }
+    // tide serves connections and zenoh runs its session on the async-std global executor and reactor,
+    // their threads outlive the plugin runtime
+    fn unload_safe(&self) -> bool {
+        false
     }
 }
 
-#[no_mangle]
-pub extern "C" fn run(running_flag: Arc<Mutex<bool>>, error_flag: Arc<Mutex<bool>>) -> i32 {
-    let _ = env_logger::try_init().map_err(|e| {
-        error!("traffic light init env log failed: {:?}", e);
-    });
-    
-    let rt = match Runtime::new() {
-        Ok(r) => r,
-        Err(e) => {
-            debug!("traffic light new runtime failed: {:?}", e);
-            return -1
+async fn light_main(road_id: String, report_topic: String, ctx: PluginContext) {
+    match light::light_loop(road_id, report_topic, &ctx).await {
+        Ok(_) => {
+            info!("traffic light loop stopped");
         },
+        Err(e) => {
+            error!("traffic light loop failed: {:?}", e.to_string());
+            ctx.fail(&format!("traffic light loop light failed: {:?}", e.to_string()));
+        }
     };
-
-    rt.block_on(async_run(running_flag, error_flag))
 }
+
+declare_plugin!("traffic_light", TrafficLight::default);
@@ -9,6 +9,7 @@ use std::convert::TryInto;
 use zenoh::*;
 use std::time::Duration;
 use log::{error};
+use rsu_plugin_sdk::PluginContext;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 pub enum LightColor {
@@ -174,7 +175,7 @@ pub fn init_lgt_status(lgt_id: &str, init_color: LightColor, remain: i64)-> Resu
 
 
 // 循环灯状态
-pub async fn light_loop(road_id: String, center_db_url: String) -> Result<(), Box<dyn Error>> {
+pub async fn light_loop(road_id: String, report_topic: String, ctx: &PluginContext) -> Result<(), Box<dyn Error>> {
     let config = Properties::default();
     let zenoh = Zenoh::new(config.into()).await?;
 
@@ -221,27 +222,25 @@ pub async fn light_loop(road_id: String, center_db_url: String) -> Result<(), Bo
         workspace.put(&light_path.clone().try_into().unwrap(), zenoh::Value::Json(value_new)).await?;
 
         // 发送给CV红绿灯数据
-        send(road_id.clone(), center_db_url.clone(), light_vec).await?;
+        send(ctx, &road_id, &report_topic, light_vec);
 
-        tokio::time::sleep_until(now.checked_add(Duration::from_secs(1)).ok_or(format!("light loop check time return None"))?).await;
+        let next_tick = now.checked_add(Duration::from_secs(1)).ok_or(format!("light loop check time return None"))?;
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_tick) => {},
+            _ = ctx.stopped() => break,
+        };
     }
-}
 
-// 1s发送一次红绿灯结果
-async fn send(road_id:String, center_db_url: String, lgt_info_vec:Vec<Light>) -> Result<(), Box<dyn Error>> {
-    let url = format!("{}{}", center_db_url, road_id);
-    match reqwest::Client::new()
-        .put(&url)
-        .json(&serde_json::json!(lgt_info_vec))
-        .send()
-        .await {
-            Ok(res) => {
-                if res.status() != 200 {
-                    error!("send traffic light status to center db failed, url:{}, reason {:?}", center_db_url, res);
-                }},
-            Err(e) => {
-                error!("send traffic light status to center db failed, url:{}, reason {:?}", center_db_url, e);
-            }
-        };
+    // 插件停止时关闭zenoh会话
+    drop(workspace);
+    zenoh.close().await?;
     Ok(())
 }
+
+// 1s发送一次红绿灯结果，交给RSU的上报通道按顺序发送
+fn send(ctx: &PluginContext, road_id: &str, report_topic: &str, lgt_info_vec: Vec<Light>) {
+    let topic = format!("{}{}", report_topic, road_id);
+    if let Err(e) = ctx.report(&topic, &lgt_info_vec) {
+        error!("report traffic light status failed, topic:{}, reason {}", topic, e);
+    }
+}
@@ -1,24 +1,18 @@
 use futures::prelude::*;
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 use std::collections::HashMap;
-use std::env;
-use std::fs;
 use std::error::Error;
-use std::path::Path;
 use std::time::Duration;
-use reqwest;
 use serde::{Deserialize, Serialize};
 use zenoh::net::*;
 use zenoh::Properties;
-use yaml_rust::{YamlLoader, YamlEmitter};
-use async_std::task;
-use std::time;
+use yaml_rust::YamlLoader;
 use log::{info, error, debug};
 use tokio;
-use tokio::runtime::Runtime;
 use tokio::time::Instant;
 extern crate lazy_static;
 use lazy_static::lazy_static;
+use rsu_plugin_sdk::{declare_plugin, PluginContext, RsuPlugin};
 
 
 #[derive(Deserialize, Serialize)] 
@@ -88,45 +82,30 @@ lazy_static! {
 }
 
 
-fn read_config(file_name: &str) -> Result<(String, String, u64), Box<dyn Error>> {
-    if !Path::new(file_name).exists() {
-        let mut dir_path_vec:Vec<&str> = file_name.split('/').collect();
-        dir_path_vec.pop();
-        fs::create_dir_all(dir_path_vec.join("/"))?;
-        fs::File::create(file_name)?;
-        generate_cfg(file_name)?;
-    }
-    let config_str = fs::read_to_string(file_name)?;
-    let config_docs = YamlLoader::load_from_str(config_str.as_str())?;
-    let config = &config_docs[0];
+fn read_config(doc: &str) -> Result<(String, String, u64), Box<dyn Error>> {
+    let config_str = if doc.trim().is_empty() { DEFAULT_CFG } else { doc };
+    let config_docs = YamlLoader::load_from_str(config_str)?;
+    let config = config_docs.get(0).ok_or("vehicle status config is empty".to_owned())?;
     let vh_zenoh_path =  String::from(config["vehicle_status_zenoh_path"].as_str()
     .ok_or("get vehicle_status_zenoh_path from vehicle status config failed".to_owned())?);
-    let ip = env::var("HOST_IP").unwrap_or("127.0.0.1".to_string());
-    let center_db_url =  String::from(config["center_db_url"].as_str()
-    .ok_or("get center_db_url from vehicle status config failed".to_owned())?)
-    .replace("127.0.0.1", &ip);
+    // reports go to the center through the rsu uplink
+    let report_topic = String::from(config["report_topic"].as_str().unwrap_or(DEFAULT_REPORT_TOPIC));
     let interval = config["interval"].as_i64()
     .ok_or("get interval from vehicle status config failed".to_owned())?;
-    Ok((vh_zenoh_path, center_db_url, interval as u64))
+    Ok((vh_zenoh_path, report_topic, interval as u64))
 }
 
 
-fn generate_cfg(cfg_path: &str) -> Result<(), Box<dyn Error>>{
-    let rsu_default = r#"---
-    vehicle_status_zenoh_path: '/demo/dds/rt/current_pose'
-    center_db_url: 'http://ip:port/rsu/rsu_id/vehicle/status/'
-    interval: 1000"#;
-    let docs = YamlLoader::load_from_str(&rsu_default)?;
-        let doc = &docs[0];
-        let mut writer = String::new();
-        let mut emitter = YamlEmitter::new(&mut writer);
-        emitter.dump(doc)?;
-        fs::write(&cfg_path, writer)?;
-        Ok(())
-}
+const DEFAULT_REPORT_TOPIC: &str = "vehicle/status/";
 
-async fn send(center_db_url: String, interval: u64) -> Result<(), Box<dyn Error>>{
-    loop {
+// used when the host supplies an empty config document
+const DEFAULT_CFG: &str = r#"---
+vehicle_status_zenoh_path: '/demo/dds/rt/current_pose'
+report_topic: 'vehicle/status/'
+interval: 1000"#;
+
+async fn send(report_topic: String, interval: u64, ctx: &PluginContext) -> Result<(), Box<dyn Error>>{
+    while ctx.is_running() {
         let now = Instant::now();
         let mut vh_status_vec: Vec<CurrentPose> = vec![];
 
@@ -138,43 +117,30 @@ async fn send(center_db_url: String, interval: u64) -> Result<(), Box<dyn Error>
             vh_status_map.clear();
         }
 
-        match reqwest::Client::new()
-            .put(&center_db_url)
-            .json(&serde_json::json!(vh_status_vec))
-            .send()
-            .await {
-                Ok(res) => {
-                    if res.status() != 200 {
-                        error!("send vehicle status to center db failed, url:{}, reason {:?}", center_db_url, res);
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        continue;
-                    }},
-                Err(e) => {
-                    error!("send vehicle status to center db failed, url:{}, reason {:?}", center_db_url, e);
-                }
-            }
-        
-        tokio::time::sleep_until(now.checked_add(Duration::from_millis(interval))
-        .ok_or(format!("vehicle status loop check time return None"))?).await;
+        // queued by the rsu and delivered in order, retried while the center is unreachable
+        if let Err(e) = ctx.report(&report_topic, &vh_status_vec) {
+            error!("report vehicle status failed, topic:{}, reason {}", report_topic, e);
+        }
+
+        let next_tick = now.checked_add(Duration::from_millis(interval))
+            .ok_or(format!("vehicle status loop check time return None"))?;
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_tick) => {},
+            _ = ctx.stopped() => break,
+        };
     }
+    Ok(())
 }
 
-async fn receive_vh_status(vh_path: String, error_flag: Arc<Mutex<bool>>) -> Result<(), String>  {
+async fn receive_vh_status(vh_path: String, ctx: PluginContext) {
     let config = Properties::default();
     // config.insert(String::from("mode"), String::from("client"));
     debug!("Opening session...");
     let session = match open(config.into()).await{
         Ok(se) => se,
         Err(e) => {
-            error!("get zenoh net session error: {:?}", e);
-
-            let mut error_flag = error_flag.lock().map_err(|e| {
-                error!("lock vehicle status error_flag failed: {:?}", e);
-                format!("lock vehicle status error_flag failed: {:?}", e)
-            })?;
-
-            *error_flag = true;
-            return Ok(())
+            ctx.fail(&format!("get zenoh net session error: {:?}", e));
+            return
         }
     };
 
@@ -191,34 +157,28 @@ async fn receive_vh_status(vh_path: String, error_flag: Arc<Mutex<bool>>) -> Res
         .await {
             Ok(sub) => sub,
             Err(e) => {
-                error!("declare_subscriber error: {:?}", e);
-
-                let mut error_flag = error_flag.lock().map_err(|e| {
-                    error!("lock vehicle status error_flag failed: {:?}", e);
-                    format!("lock vehicle status error_flag failed: {:?}", e)
-                })?;
-    
-                *error_flag = true;
-                return Ok(())
+                ctx.fail(&format!("declare_subscriber error: {:?}", e));
+                return
             },
         };
 
     let stream = subscriber.stream();
     let id:String = String::from("car_id");
-    while let Some(d) = stream.next().await{
+    loop {
+        let d = tokio::select! {
+            d = stream.next() => match d {
+                Some(d) => d,
+                None => break,
+            },
+            _ = ctx.stopped() => break,
+        };
         let bs = d.payload.to_vec();
         {
             let vh_status: CurrentPose = match CurrentPose::new(&bs) {
                 Ok(cp) => cp,
                 Err(e) => {
-                    error!("new CurrentPose failed: {:?}", e);
-                    let mut error_flag = error_flag.lock().map_err(|e| {
-                        error!("lock error_flag failed: {:?}", e);
-                        format!("lock error_flag failed: {:?}", e)
-                    })?;
-        
-                    *error_flag = true;
-                    return Ok(())
+                    ctx.fail(&format!("new CurrentPose failed: {:?}", e));
+                    return
                 }
             };
 
@@ -227,96 +187,67 @@ async fn receive_vh_status(vh_path: String, error_flag: Arc<Mutex<bool>>) -> Res
             let mut vh_status_map = match VEHICLESTATUSMAP.lock(){
                 Ok(map) => map,
                 Err(e) => {
-                    error!("lock vehicle status map failed: {:?}", e);
-                    let mut error_flag = error_flag.lock().map_err(|e| {
-                        error!("lock error_flag failed: {:?}", e);
-                        format!("lock error_flag failed: {:?}", e)
-                    })?;
-        
-                    *error_flag = true;
-                    return Ok(())
+                    ctx.fail(&format!("lock vehicle status map failed: {:?}", e));
+                    return
                 }
             };
             vh_status_map.insert(String::from(&id), vh_status);
         }
     }
-    Ok(())
-}
 
+    // 插件停止时注销订阅并关闭zenoh会话
+    if let Err(e) = subscriber.undeclare().await {
+        error!("undeclare vehicle status subscriber failed: {:?}", e);
+    }
+    if let Err(e) = session.close().await {
+        error!("close zenoh net session failed: {:?}", e);
+    }
+}
 
-async fn plugin_main(error_flag: Arc<Mutex<bool>>) -> Result<(), String>{
-    let cfg_path = "./config/plugins/vehicle_status.yaml";
-    let (vh_zenoh_path, center_db_url, interval) = match read_config(&cfg_path){
-        Ok((vh_zenoh_path, center_db_url, interval)) => (vh_zenoh_path, center_db_url, interval),
-        Err(e) => {
-            {
-                let mut error_flag = error_flag.lock().map_err(|e| {
-                    error!("lock vehicle status error_flag failed: {:?}", e);
-                    format!("lock vehicle status error_flag failed: {:?}", e)
-                })?;
-                *error_flag = true;
-            }
-            error!("read vehicle status config failed: {:?}", e.to_string());
-            return Err(format!("read vehicle status config failed: {:?}", e.to_string()))
-        },
-    };
 
-    let error_flag_clone = Arc::clone(&error_flag);
-    tokio::spawn(receive_vh_status(vh_zenoh_path, error_flag_clone));
-    
-    match send(center_db_url, interval).await{
-        Ok(_) => {
-            info!("vehicle status plugin server start successful");
-        },
-        Err(e) => {
-            error!("vehicle status plugin server failed: {:?}", e);
-            info!("sleep 2s ...");
-            {
-                let mut error_flag = error_flag.lock().map_err(|e| {
-                    error!("lock vehicle status error_flag failed: {:?}", e);
-                    format!("lock vehicle status error_flag failed: {:?}", e)
-                })?;
-                *error_flag = true;
-            }
-        }
-
-    };
-    Ok(())
+#[derive(Default)]
+struct VehicleStatus {
+    vh_zenoh_path: String,
+    report_topic: String,
+    interval: u64,
 }
 
-async fn async_run(running_flag: Arc<Mutex<bool>>, error_flag: Arc<Mutex<bool>>) -> i32 {
-    tokio::spawn(plugin_main(error_flag));
+impl RsuPlugin for VehicleStatus {
+    fn config(&mut self, doc: &str) -> Result<(), String> {
+        let (vh_zenoh_path, report_topic, interval) = read_config(doc)
+            .map_err(|e| format!("read vehicle status config failed: {:?}", e.to_string()))?;
+        self.vh_zenoh_path = vh_zenoh_path;
+        self.report_topic = report_topic;
+        self.interval = interval;
+        Ok(())
+    }
 
-    loop {
-        task::sleep(time::Duration::from_secs(1)).await;
-        let the_flag = match running_flag.lock(){
-            Ok(f) => f,
-            Err(e) => {
-                debug!("lock vehicle status running_flag failed: {:?}", e);
-                return -1
-            },
-        };
+    fn start(&mut self, ctx: &PluginContext) -> Result<(), String> {
+        ctx.spawn(receive_vh_status(self.vh_zenoh_path.clone(), ctx.clone()));
+        ctx.spawn(send_main(self.report_topic.clone(), self.interval, ctx.clone()));
+        Ok(())
+    }
 
-        if !*the_flag {
-            info!("plugin vehicle status stopped");
-            return 0
-        }
+    fn stop(&mut self) -> Result<(), String> {
+        info!("plugin vehicle status stopped");
+        Ok(())
     }
-}
 
-#[no_mangle]
-pub extern "C" fn run(running_flag: Arc<Mutex<bool>>, error_flag: Arc<Mutex<bool>>) -> i32 {
-    let _ = env_logger::try_init().map_err(|e| {
-        error!("vehicle status init env log failed: {:?}", e);
-    });
+    // zenoh runs its session on the async-std global executor and reactor, their threads outlive the plugin runtime
+    fn unload_safe(&self) -> bool {
+        false
+    }
+}
 
-    let rt = match Runtime::new() {
-        Ok(r) => r,
-        Err(e) => {
-            debug!("vehicle status new runtime failed: {:?}", e);
-            return -1
+async fn send_main(report_topic: String, interval: u64, ctx: PluginContext) {
+    match send(report_topic, interval, &ctx).await{
+        Ok(_) => {
+            info!("vehicle status plugin server stopped");
         },
+        Err(e) => {
+            ctx.fail(&format!("vehicle status plugin server failed: {:?}", e));
+        }
     };
-
-    rt.block_on(async_run(running_flag, error_flag))
 }
+
+declare_plugin!("vehicle_status", VehicleStatus::default);
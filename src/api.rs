@@ -0,0 +1,290 @@
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use percent_encoding::percent_decode;
+use url::form_urlencoded;
+use tide::{Request, Response, StatusCode};
+use crate::plugin::PluginError;
+
+
+// Request bodies of the management API.
+// Besides json, the legacy percent-encoded json and form bodies (name=traffic_light&active=true) are accepted.
+
+#[derive(Deserialize, Debug)]
+pub struct ActivateRequest {
+    pub name: String,
+    #[serde(deserialize_with = "lenient_bool")]
+    pub active: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddRequest {
+    pub name: String,
+    pub path: String,
+    #[serde(deserialize_with = "lenient_bool")]
+    pub active: bool,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveRequest {
+    pub name: String,
+}
+
+// text fields of the multipart /plugin/upload body, the library itself is the `file` field
+#[derive(Deserialize, Debug)]
+pub struct UploadRequest {
+    pub name: String,
+    pub sha256: String,
+    // hex ed25519 signature of the library, stored next to it as `<lib>.sig`
+    #[serde(default)]
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub active: bool,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReloadRequest {
+    pub name: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default, deserialize_with = "lenient_opt_u64")]
+    pub grace: Option<u64>,
+}
+
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    TooLarge,
+    Internal,
+}
+
+impl ErrorCode {
+    fn http_status(&self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BadRequest,
+            ErrorCode::Unauthorized => StatusCode::Unauthorized,
+            ErrorCode::Forbidden => StatusCode::Forbidden,
+            ErrorCode::NotFound => StatusCode::NotFound,
+            ErrorCode::Conflict => StatusCode::Conflict,
+            ErrorCode::TooLarge => StatusCode::PayloadTooLarge,
+            ErrorCode::Internal => StatusCode::InternalServerError,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    // more about the failure, e.g. which steps of a reconcile failed
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: String) -> ApiError {
+        ApiError {code, message, details: None}
+    }
+
+    pub fn with_details(mut self, details: Value) -> ApiError {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn bad_request(message: String) -> ApiError {
+        ApiError::new(ErrorCode::BadRequest, message)
+    }
+
+    // Error envelope, `status` and `message` are kept for the clients written against the old API
+    pub fn to_json(&self) -> Value {
+        let mut error = json!({ "code": self.code, "message": self.message });
+        if let Some(details) = &self.details {
+            error["details"] = details.clone();
+        }
+        json!({
+            "status": -1,
+            "message": self.message,
+            "error": error,
+        })
+    }
+
+    pub fn into_response(self) -> Response {
+        let mut res = Response::builder(self.code.http_status())
+            .body(self.to_json())
+            .build();
+        if self.code == ErrorCode::Unauthorized {
+            res.insert_header("WWW-Authenticate", "Bearer");
+        }
+        res
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<PluginError> for ApiError {
+    fn from(e: PluginError) -> ApiError {
+        let code = match e {
+            PluginError::Invalid(_) => ErrorCode::BadRequest,
+            PluginError::NotFound(_) => ErrorCode::NotFound,
+            PluginError::Conflict(_) => ErrorCode::Conflict,
+            PluginError::Failed(_) => ErrorCode::Internal,
+        };
+        ApiError::new(code, e.to_string())
+    }
+}
+
+// turn the result of a handler into the response, successful bodies are sent with 200
+pub fn respond(ret: Result<Value, ApiError>) -> Response {
+    match ret {
+        Ok(body) => Response::builder(StatusCode::Ok).body(body).build(),
+        Err(e) => e.into_response(),
+    }
+}
+
+pub fn ok_message(message: String) -> Value {
+    json!({ "status": 1, "message": message })
+}
+
+pub async fn parse_body<T: DeserializeOwned, S>(req: &mut Request<S>) -> Result<T, ApiError> {
+    let body = req.body_string().await
+                    .map_err(|e| ApiError::bad_request(format!("read request body failed: {}", e)))?;
+    parse_str(&body)
+}
+
+fn parse_str<T: DeserializeOwned>(body: &str) -> Result<T, ApiError> {
+    let value = match serde_json::from_str::<Value>(body) {
+        Ok(v) => v,
+        Err(_) => {
+            // legacy clients percent-encode the json body
+            let decoded = percent_decode(body.as_bytes()).decode_utf8()
+                            .map_err(|e| ApiError::bad_request(format!("request body is not utf-8: {}", e)))?;
+            match serde_json::from_str::<Value>(&decoded) {
+                Ok(v) => v,
+                Err(e) if !body.contains('=') => {
+                    return Err(ApiError::bad_request(format!("param parse into json wrong: {}", e)))
+                },
+                Err(_) => {
+                    let pairs = form_urlencoded::parse(body.as_bytes()).map(|(k, v)| (k.into_owned(), v.into_owned())).collect();
+                    fields_to_json(pairs)
+                },
+            }
+        }
+    };
+    if !value.is_object() {
+        return Err(ApiError::bad_request(String::from("request body must be an object")))
+    }
+    serde_json::from_value(value).map_err(|e| ApiError::bad_request(format!("invalid request: {}", e)))
+}
+
+// deserialize form or multipart text fields
+pub fn parse_fields<T: DeserializeOwned>(pairs: Vec<(String, String)>) -> Result<T, ApiError> {
+    serde_json::from_value(fields_to_json(pairs)).map_err(|e| ApiError::bad_request(format!("invalid request: {}", e)))
+}
+
+// fields become strings, a repeated field becomes an array
+fn fields_to_json(pairs: Vec<(String, String)>) -> Value {
+    let mut fields: Map<String, Value> = Map::new();
+    for (key, value) in pairs {
+        let key = key.trim_end_matches("[]").to_string();
+        let value = Value::String(value);
+        match fields.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(prev) => {
+                let first = prev.take();
+                *prev = Value::Array(vec![first, value]);
+            },
+            None => {
+                // depends_on is a list even with a single entry
+                let value = if key == "depends_on" { Value::Array(vec![value]) } else { value };
+                fields.insert(key, value);
+            },
+        }
+    }
+    Value::Object(fields)
+}
+
+fn lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Bool(b) => Ok(b),
+        Value::String(s) if s == "true" || s == "1" => Ok(true),
+        Value::String(s) if s == "false" || s == "0" => Ok(false),
+        Value::Number(n) if n.as_i64() == Some(1) => Ok(true),
+        Value::Number(n) if n.as_i64() == Some(0) => Ok(false),
+        v => Err(serde::de::Error::custom(format!("expect a bool, got {}", v))),
+    }
+}
+
+fn lenient_opt_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(None),
+        Value::Number(n) if n.is_u64() => Ok(n.as_u64()),
+        Value::String(s) => s.parse::<u64>().map(Some).map_err(serde::de::Error::custom),
+        v => Err(serde::de::Error::custom(format!("expect a positive integer, got {}", v))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_str_reads_json() {
+        let req: ActivateRequest = parse_str(r#"{"name": "traffic_light", "active": true}"#).unwrap();
+        assert_eq!(req.name, "traffic_light");
+        assert!(req.active);
+    }
+
+    #[test]
+    fn parse_str_reads_percent_encoded_json() {
+        let req: AddRequest = parse_str("%7B%22name%22%3A%22camera%22%2C%22path%22%3A%22libcamera.so%22%2C%22active%22%3A%221%22%7D").unwrap();
+        assert_eq!(req.name, "camera");
+        assert_eq!(req.path, "libcamera.so");
+        assert!(req.active);
+        assert!(req.depends_on.is_empty());
+    }
+
+    #[test]
+    fn parse_str_reads_legacy_forms() {
+        let req: ActivateRequest = parse_str("name=traffic_light&active=false").unwrap();
+        assert_eq!(req.name, "traffic_light");
+        assert!(!req.active);
+
+        let req: AddRequest = parse_str("name=camera&path=%2Fopt%2Flibcamera.so&active=0&depends_on=gps").unwrap();
+        assert_eq!(req.path, "/opt/libcamera.so");
+        assert_eq!(req.depends_on, vec!["gps"]);
+        let req: AddRequest = parse_str("name=camera&path=libcamera.so&active=1&depends_on[]=gps&depends_on[]=can").unwrap();
+        assert_eq!(req.depends_on, vec!["gps", "can"]);
+
+        let req: ReloadRequest = parse_str("name=camera&grace=5").unwrap();
+        assert_eq!(req.grace, Some(5));
+        assert_eq!(req.path, None);
+    }
+
+    #[test]
+    fn parse_str_rejects_bad_bodies() {
+        let bad_request = |ret: Result<ActivateRequest, ApiError>| ret.unwrap_err().code == ErrorCode::BadRequest;
+        assert!(bad_request(parse_str("not json")));
+        assert!(bad_request(parse_str("[1, 2]")));
+        assert!(bad_request(parse_str("%FF%FE")));
+        assert!(bad_request(parse_str(r#"{"name": "traffic_light", "active": "yes"}"#)));
+        assert!(bad_request(parse_str("name=traffic_light")));
+        let ret: Result<ReloadRequest, ApiError> = parse_str(r#"{"name": "camera", "grace": -1}"#);
+        assert_eq!(ret.unwrap_err().code, ErrorCode::BadRequest);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::BufReader;
+use hmac::{Hmac, Mac, NewMac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use yaml_rust::Yaml;
+use tide::Request;
+use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig};
+use rustls::internal::pemfile;
+use crate::api::{ApiError, ErrorCode};
+
+
+// Roles of the management API, a role is granted everything the lower ones are
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    // read plugin state
+    Viewer,
+    // start, stop and reload plugins from their configured library
+    Operator,
+    // add and remove plugins, load libraries from a new path
+    Admin,
+}
+
+impl Role {
+    fn parse(role: &str) -> Result<Role, String> {
+        match role {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role: {}, expect viewer/operator/admin", role)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    name: String,
+    token: String,
+    role: Role,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    cert: String,
+    key: String,
+    // clients have to present a certificate signed by this ca (mutual TLS)
+    client_ca: Option<String>,
+    // role of a client authenticated by its certificate that sends no token
+    client_role: Role,
+}
+
+// `auth` section of rsu.yaml, without tokens and client certificates the API is open
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    tokens: Vec<Token>,
+    tls: Option<TlsSettings>,
+}
+
+impl Auth {
+    pub fn from_yaml(cfg: &Yaml) -> Result<Auth, String> {
+        let mut auth = Auth::default();
+        if let Some(tokens) = cfg["tokens"].as_vec() {
+            for token in tokens {
+                let name = token["name"].as_str().ok_or("read auth token name failed")?;
+                let secret = token["token"].as_str().ok_or(format!("read auth token[{}] token failed", name))?;
+                if secret.is_empty() {
+                    return Err(format!("auth token[{}] is empty", name))
+                }
+                let role = Role::parse(token["role"].as_str().ok_or(format!("read auth token[{}] role failed", name))?)?;
+                auth.tokens.push(Token {name: String::from(name), token: String::from(secret), role});
+            }
+        }
+
+        let tls = &cfg["tls"];
+        if !tls.is_badvalue() && !tls.is_null() {
+            let client_role = match tls["client_role"].as_str() {
+                Some(role) => Role::parse(role)?,
+                None => Role::Viewer,
+            };
+            auth.tls = Some(TlsSettings {
+                cert: String::from(tls["cert"].as_str().ok_or("read auth tls cert failed")?),
+                key: String::from(tls["key"].as_str().ok_or("read auth tls key failed")?),
+                client_ca: tls["client_ca"].as_str().map(String::from),
+                client_role,
+            });
+        }
+
+        if !auth.enabled() {
+            warn!("management API authentication is disabled, configure auth tokens or tls client_ca in rsu.yaml");
+        }
+        Ok(auth)
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.client_cert_required()
+    }
+
+    // callers that can not present a client certificate, e.g. over zenoh, need a token
+    pub fn has_tokens(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    fn client_cert_required(&self) -> bool {
+        self.tls.as_ref().map(|t| t.client_ca.is_some()).unwrap_or(false)
+    }
+
+    // Check that the caller has at least `role`. A bearer token decides the role, without one a
+    // client authenticated by its certificate gets `client_role`.
+    pub fn require<S>(&self, req: &Request<S>, role: Role) -> Result<Role, ApiError> {
+        if !self.enabled() {
+            return Ok(Role::Admin)
+        }
+
+        let granted = match req.header("Authorization").map(|h| h.last().as_str()) {
+            Some(header) => match header.strip_prefix("Bearer ") {
+                Some(secret) => self.token_role(secret.trim())?,
+                None => return Err(ApiError::new(ErrorCode::Unauthorized, String::from("expect a bearer token"))),
+            },
+            None if self.client_cert_required() => self.tls.as_ref().map(|t| t.client_role).unwrap_or(Role::Viewer),
+            None => return Err(ApiError::new(ErrorCode::Unauthorized, String::from("authentication required"))),
+        };
+        check_role(granted, role)
+    }
+
+    // Same as `require` for callers whose requests are seen by others in transit, e.g. over zenoh:
+    // the token never travels, `sig` is the hex HMAC-SHA256 of `message` keyed with the token named `key`
+    pub fn require_signature(&self, key: Option<&str>, message: &str, sig: Option<&str>, role: Role) -> Result<Role, ApiError> {
+        if !self.enabled() {
+            return Ok(Role::Admin)
+        }
+        let (key, sig) = match (key, sig) {
+            (Some(key), Some(sig)) => (key, sig),
+            _ => return Err(ApiError::new(ErrorCode::Unauthorized, String::from("authentication required"))),
+        };
+        let token = self.tokens.iter().find(|t| t.name == key)
+                        .ok_or_else(|| ApiError::new(ErrorCode::Unauthorized, String::from("invalid signature")))?;
+        let mut mac = Hmac::<Sha256>::new_varkey(token.token.as_bytes())
+                        .map_err(|_| ApiError::new(ErrorCode::Unauthorized, String::from("invalid signature")))?;
+        mac.update(message.as_bytes());
+        let expected: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        if !constant_time_eq(expected.as_bytes(), sig.to_ascii_lowercase().as_bytes()) {
+            return Err(ApiError::new(ErrorCode::Unauthorized, String::from("invalid signature")))
+        }
+        debug!("request signed by token[{}]", token.name);
+        check_role(token.role, role)
+    }
+
+    fn token_role(&self, secret: &str) -> Result<Role, ApiError> {
+        match self.tokens.iter().find(|t| constant_time_eq(t.token.as_bytes(), secret.as_bytes())) {
+            Some(token) => {
+                debug!("request authenticated by token[{}]", token.name);
+                Ok(token.role)
+            },
+            None => Err(ApiError::new(ErrorCode::Unauthorized, String::from("invalid token"))),
+        }
+    }
+
+    // rustls config of the management server, None to serve plain http
+    pub fn tls_config(&self) -> Result<Option<ServerConfig>, String> {
+        let tls = match &self.tls {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let mut cert_reader = BufReader::new(File::open(&tls.cert).map_err(|e| format!("open tls cert {} failed: {:?}", tls.cert, e))?);
+        let certs = pemfile::certs(&mut cert_reader).map_err(|_| format!("read tls cert {} failed", tls.cert))?;
+        let mut key_reader = BufReader::new(File::open(&tls.key).map_err(|e| format!("open tls key {} failed: {:?}", tls.key, e))?);
+        let mut keys = pemfile::pkcs8_private_keys(&mut key_reader).map_err(|_| format!("read tls key {} failed", tls.key))?;
+        if keys.is_empty() {
+            let mut key_reader = BufReader::new(File::open(&tls.key).map_err(|e| format!("open tls key {} failed: {:?}", tls.key, e))?);
+            keys = pemfile::rsa_private_keys(&mut key_reader).map_err(|_| format!("read tls key {} failed", tls.key))?;
+        }
+        let key = keys.into_iter().next().ok_or(format!("no private key in {}", tls.key))?;
+
+        let mut config = match &tls.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                let mut ca_reader = BufReader::new(File::open(ca).map_err(|e| format!("open tls client ca {} failed: {:?}", ca, e))?);
+                roots.add_pem_file(&mut ca_reader).map_err(|_| format!("read tls client ca {} failed", ca))?;
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            },
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        config.set_single_cert(certs, key).map_err(|e| format!("set tls cert failed: {:?}", e))?;
+        Ok(Some(config))
+    }
+}
+
+fn check_role(granted: Role, role: Role) -> Result<Role, ApiError> {
+    if granted < role {
+        return Err(ApiError::new(ErrorCode::Forbidden, format!("role {:?} is required", role)))
+    }
+    Ok(granted)
+}
+
+// compare secrets without leaking the matching prefix length through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
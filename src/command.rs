@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use yaml_rust::Yaml;
+use crate::api::{ActivateRequest, AddRequest, ApiError, ErrorCode, ReloadRequest, RemoveRequest};
+use crate::events::{self, EventKind};
+use crate::server;
+
+
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+// added to the poll timeout, the center answers a poll without commands after `wait` seconds
+const POLL_MARGIN: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// ids of the latest commands, a command the center sends again is answered with its first result
+const DONE_SIZE: usize = 256;
+
+// The `command` section of rsu.yaml, the center sends commands over a connection opened by the rsu,
+// so it can manage an rsu it can not reach, e.g. behind a NAT
+#[derive(Debug, Clone)]
+pub struct CommandCfg {
+    pub url: String,
+    // sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    // how long the center may hold a poll open, in seconds
+    pub poll_timeout: u64,
+}
+
+impl CommandCfg {
+    // None when no `url` is configured, the channel is optional
+    pub fn from_yaml(cfg: &Yaml) -> Result<Option<CommandCfg>, String> {
+        let url = match cfg["url"].as_str() {
+            Some(url) if !url.is_empty() => String::from(url),
+            _ => return Ok(None),
+        };
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("command.url {} must be an http or https url", url))
+        }
+        let poll_timeout = cfg["poll_timeout"].as_i64().map(|t| t as u64).unwrap_or(DEFAULT_POLL_TIMEOUT);
+        if poll_timeout == 0 {
+            return Err(String::from("command.poll_timeout must be greater than 0"))
+        }
+        Ok(Some(CommandCfg {url, token: cfg["token"].as_str().map(String::from), poll_timeout}))
+    }
+}
+
+// A command from the center, the fields besides `id` and `action` are the body of the matching API
+#[derive(Deserialize, Debug)]
+struct Command {
+    id: String,
+    action: String,
+    #[serde(flatten)]
+    params: Value,
+}
+
+// Outcome of a command, sent back with the next poll
+#[derive(Serialize, Debug, Clone)]
+struct CommandResult {
+    id: String,
+    action: String,
+    ok: bool,
+    // the API response on success, the API error otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+// Poll the center for commands until the rsu exits. Every poll carries the results of the
+// commands executed since the last successful poll, so a result is sent again until it arrived.
+pub async fn run(cfg: CommandCfg) {
+    let client = match reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(Duration::from_secs(cfg.poll_timeout) + POLL_MARGIN)
+        .build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("start command channel failed, create http client failed: {:?}", e);
+            return
+        }
+    };
+    info!("command channel polling {}", cfg.url);
+
+    let mut pending: Vec<CommandResult> = vec![];
+    let mut done: VecDeque<CommandResult> = VecDeque::with_capacity(DONE_SIZE);
+    let mut backoff: Option<Duration> = None;
+    loop {
+        let commands = match poll(&client, &cfg, &pending).await {
+            Ok(commands) => {
+                if backoff.take().is_some() {
+                    info!("command channel connected to {} again", cfg.url);
+                }
+                pending.clear();
+                commands
+            },
+            Err(e) => {
+                let wait = backoff.map_or(Duration::from_secs(1), |b| std::cmp::min(b * 2, MAX_BACKOFF));
+                if backoff.is_none() {
+                    warn!("command channel poll {} failed, retry in {:?}: {}", cfg.url, wait, e);
+                }
+                backoff = Some(wait);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+        };
+
+        // in order, a command may depend on the one before it
+        for command in commands {
+            let command: Command = match serde_json::from_value(command) {
+                Ok(command) => command,
+                Err(e) => {
+                    error!("command channel dropped an invalid command: {}", e);
+                    continue;
+                }
+            };
+            if let Some(result) = done.iter().find(|r| r.id == command.id) {
+                info!("command {} was already executed, send its result again", command.id);
+                pending.push(result.clone());
+                continue;
+            }
+            let result = execute(command).await;
+            if done.len() == DONE_SIZE {
+                done.pop_front();
+            }
+            done.push_back(result.clone());
+            pending.push(result);
+        }
+    }
+}
+
+// POST the pending results, the center answers with a json array of commands, or 204 without commands
+async fn poll(client: &reqwest::Client, cfg: &CommandCfg, results: &[CommandResult]) -> Result<Vec<Value>, String> {
+    let mut req = client.post(&cfg.url).json(&json!({ "wait": cfg.poll_timeout, "results": results }));
+    if let Some(token) = &cfg.token {
+        req = req.bearer_auth(token);
+    }
+    let res = req.send().await.map_err(|e| format!("{:?}", e))?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(format!("center answered {}", status))
+    }
+    if status == reqwest::StatusCode::NO_CONTENT {
+        return Ok(vec![])
+    }
+    let body = res.text().await.map_err(|e| format!("read commands failed: {:?}", e))?;
+    if body.trim().is_empty() {
+        return Ok(vec![])
+    }
+    serde_json::from_str(&body).map_err(|e| format!("commands are not a json array: {}", e))
+}
+
+async fn execute(command: Command) -> CommandResult {
+    info!("received command {} {}: {}", command.id, command.action, command.params);
+    let ret = dispatch(&command.action, command.params).await;
+    let (ok, message) = match &ret {
+        Ok(body) => (true, String::from(body["message"].as_str().unwrap_or_default())),
+        Err(e) => (false, e.message.clone()),
+    };
+    if !ok {
+        warn!("command {} {} failed: {}", command.id, command.action, message);
+    }
+    events::publish(EventKind::Command {id: command.id.clone(), action: command.action.clone(), ok, message});
+    match ret {
+        Ok(body) => CommandResult {id: command.id, action: command.action, ok, result: Some(body), error: None},
+        Err(e) => CommandResult {id: command.id, action: command.action, ok, result: None,
+                                 error: Some(json!({ "code": e.code, "message": e.message, "details": e.details }))},
+    }
+}
+
+// The center has the rights of an admin, the commands run the same code as the API
+async fn dispatch(action: &str, params: Value) -> Result<Value, ApiError> {
+    match action {
+        "start" | "stop" => {
+            let name = String::from(params["name"].as_str().ok_or_else(|| ApiError::bad_request(String::from("need field: name")))?);
+            let active = action == "start";
+            blocking(move || server::activate(ActivateRequest {name, active})).await
+        },
+        "add" => {
+            let body: AddRequest = parse(params)?;
+            blocking(move || server::add(body)).await
+        },
+        "remove" => {
+            let body: RemoveRequest = parse(params)?;
+            blocking(move || server::remove(body)).await
+        },
+        "reload" => {
+            let body: ReloadRequest = parse(params)?;
+            blocking(move || server::reload(body)).await
+        },
+        // `plugins` is the desired plugins document of PUT /plugins
+        "config" => {
+            let dry_run = params["dry_run"].as_bool().unwrap_or(false);
+            let doc = json!({ "plugins": params["plugins"] }).to_string();
+            blocking(move || server::reconcile(&doc, dry_run)).await
+        },
+        _ => Err(ApiError::bad_request(format!("unknown command action {}, expect start, stop, add, remove, reload or config", action))),
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, ApiError> {
+    serde_json::from_value(params).map_err(|e| ApiError::bad_request(format!("invalid command: {}", e)))
+}
+
+// the operations wait for the plugin manager and for plugins to start or stop
+async fn blocking<F>(f: F) -> Result<Value, ApiError>
+where
+    F: FnOnce() -> Result<Value, ApiError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await
+        .unwrap_or_else(|e| Err(ApiError::new(ErrorCode::Internal, format!("command panicked: {:?}", e))))
+}
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use lazy_static::lazy_static;
+use crate::plugin::{unix_now, PluginState};
+
+
+// events kept for clients reconnecting with Last-Event-ID
+const HISTORY_SIZE: usize = 256;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum EventKind {
+    // lifecycle transition of a plugin
+    State { plugin: String, from: PluginState, to: PluginState },
+    // a running plugin reported an error or could not be restarted by `check_plugin`
+    CheckFailed { plugin: String, reason: String },
+    // the config document handed to a plugin changed
+    Config { plugin: String, config: Value },
+    // plugins.yaml changed: a plugin was added, removed or points at a new library
+    Added { plugin: String, path: String },
+    Removed { plugin: String },
+    Library { plugin: String, path: String },
+    // a call to the management API that changes something
+    Api { action: String, remote: Option<String>, ok: bool, message: String },
+    // a command from the center over the command channel
+    Command { id: String, action: String, ok: bool, message: String },
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::State {..} => "state",
+            EventKind::CheckFailed {..} => "check-failed",
+            EventKind::Config {..} => "config",
+            EventKind::Added {..} => "added",
+            EventKind::Removed {..} => "removed",
+            EventKind::Library {..} => "library",
+            EventKind::Api {..} => "api",
+            EventKind::Command {..} => "command",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    // increases by one per event, sent as the SSE id
+    pub seq: u64,
+    // unix timestamp in seconds
+    pub time: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+struct EventBus {
+    next_seq: u64,
+    history: VecDeque<Event>,
+    sender: broadcast::Sender<Event>,
+}
+
+lazy_static! {
+    static ref BUS: Mutex<EventBus> = {
+        let (sender, _) = broadcast::channel(HISTORY_SIZE);
+        Mutex::new(EventBus {next_seq: 1, history: VecDeque::with_capacity(HISTORY_SIZE), sender})
+    };
+}
+
+// Send an event to every subscriber, it is dropped when nobody listens
+pub fn publish(kind: EventKind) {
+    let mut bus = BUS.lock().unwrap();
+    let event = Event {seq: bus.next_seq, time: unix_now(), kind};
+    bus.next_seq += 1;
+    if bus.history.len() == HISTORY_SIZE {
+        bus.history.pop_front();
+    }
+    bus.history.push_back(event.clone());
+    let _ = bus.sender.send(event);
+}
+
+// Subscribe to new events, together with the kept events after `last_seq`.
+// Both are taken under the bus lock, so no event is missed or sent twice.
+pub fn subscribe(last_seq: Option<u64>) -> (Vec<Event>, broadcast::Receiver<Event>) {
+    let bus = BUS.lock().unwrap();
+    let missed = match last_seq {
+        Some(seq) => bus.history.iter().filter(|e| e.seq > seq).cloned().collect(),
+        None => vec![],
+    };
+    (missed, bus.sender.subscribe())
+}
@@ -0,0 +1,192 @@
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use log::info;
+use serde_json::{json, Value};
+use yaml_rust::Yaml;
+use yaml_rust::yaml::Hash;
+use lazy_static::lazy_static;
+use rsu_plugin_abi::RSU_PLUGIN_ABI_VERSION;
+use crate::plugin::{unix_now, yaml_to_json, PluginView};
+
+
+// read in order when rsu.yaml has no `rsu_id`
+const MACHINE_ID_FILES: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+// length of the machine id prefix in a derived rsu id
+const MACHINE_ID_PREFIX: usize = 12;
+
+// Who this RSU is, the values of the `${...}` variables in rsu.yaml and the plugin configs
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub rsu_id: String,
+    // `HOST_IP` of the environment, 127.0.0.1 if it is not set
+    pub host_ip: String,
+    // `location` of rsu.yaml, announced to the center as is
+    pub location: Option<Value>,
+}
+
+lazy_static! {
+    // set by main before the plugins are loaded, their configs are expanded with it
+    static ref IDENTITY: RwLock<Option<Identity>> = RwLock::new(None);
+}
+
+impl Identity {
+    // `rsu_id` and `location` are read before the variables are expanded, they can not use them
+    pub fn from_yaml(config: &Yaml) -> Result<Identity, String> {
+        let rsu_id = match config["rsu_id"].as_str() {
+            Some(rsu_id) => String::from(rsu_id),
+            None => {
+                let rsu_id = derive_rsu_id()?;
+                info!("rsu_id is not configured, derived {} from the machine id", rsu_id);
+                rsu_id
+            }
+        };
+        if rsu_id.is_empty() || !rsu_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+            return Err(format!("rsu_id {:?} may only contain letters, digits, '-', '_' and '.'", rsu_id))
+        }
+        let host_ip = match env::var("HOST_IP") {
+            Ok(host_ip) => {
+                host_ip.parse::<IpAddr>().map_err(|_| format!("HOST_IP {:?} is not an ip address", host_ip))?;
+                host_ip
+            },
+            Err(_) => String::from("127.0.0.1"),
+        };
+        let location = match &config["location"] {
+            Yaml::BadValue | Yaml::Null => None,
+            location => Some(yaml_to_json(location)),
+        };
+        Ok(Identity {rsu_id, host_ip, location})
+    }
+
+    // Replace `${RSU_ID}` and `${HOST_IP}` in `text`, any other variable is an error
+    pub fn expand(&self, text: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let end = rest[start..].find('}').ok_or_else(|| format!("unterminated variable in {:?}", &rest[start..]))?;
+            match &rest[start + 2..start + end] {
+                "RSU_ID" => out.push_str(&self.rsu_id),
+                "HOST_IP" => out.push_str(&self.host_ip),
+                name => return Err(format!("unknown variable ${{{}}}, expect ${{RSU_ID}} or ${{HOST_IP}}", name)),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    // Expand the string values of `doc`, keys, comments and the other scalars are left as they are
+    pub fn expand_yaml(&self, doc: &Yaml) -> Result<Yaml, String> {
+        match doc {
+            Yaml::String(s) => self.expand(s).map(Yaml::String),
+            Yaml::Array(items) => items.iter().map(|item| self.expand_yaml(item)).collect::<Result<_, _>>().map(Yaml::Array),
+            Yaml::Hash(hash) => {
+                let mut out = Hash::new();
+                for (key, value) in hash {
+                    let value = self.expand_yaml(value).map_err(|e| format!("{}: {}", key.as_str().unwrap_or("?"), e))?;
+                    out.insert(key.clone(), value);
+                }
+                Ok(Yaml::Hash(out))
+            },
+            other => Ok(other.clone()),
+        }
+    }
+
+    // Body of the registration report sent to the center at startup
+    pub fn registration(&self, port: &str, plugins: Vec<PluginView>) -> Value {
+        json!({
+            "rsu_id": self.rsu_id,
+            "version": env!("CARGO_PKG_VERSION"),
+            "plugin_abi": RSU_PLUGIN_ABI_VERSION,
+            "host_ip": self.host_ip,
+            "port": port,
+            "location": self.location,
+            "plugins": plugins,
+            "started_at": unix_now(),
+        })
+    }
+}
+
+pub fn init(identity: Identity) {
+    *IDENTITY.write().unwrap() = Some(identity);
+}
+
+// Expand the variables in the string values of `doc` with the identity set by `init`
+pub fn expand_yaml(doc: &Yaml) -> Result<Yaml, String> {
+    match &*IDENTITY.read().map_err(|e| format!("lock rsu identity failed: {:?}", e))? {
+        Some(identity) => identity.expand_yaml(doc),
+        None => Err(String::from("rsu identity is not initialized")),
+    }
+}
+
+fn derive_rsu_id() -> Result<String, String> {
+    for file in MACHINE_ID_FILES.iter() {
+        if let Ok(id) = fs::read_to_string(file) {
+            let id = id.trim();
+            if id.len() >= MACHINE_ID_PREFIX {
+                return Ok(format!("rsu-{}", id.chars().take(MACHINE_ID_PREFIX).collect::<String>()))
+            }
+        }
+    }
+    Err(format!("rsu_id is not configured and no machine id found in {:?}", MACHINE_ID_FILES))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn identity() -> Identity {
+        Identity {rsu_id: String::from("rsu-0001"), host_ip: String::from("10.0.0.8"), location: None}
+    }
+
+    #[test]
+    fn expand_replaces_known_variables() {
+        assert_eq!(identity().expand("http://${HOST_IP}:8000/rsu/${RSU_ID}/").unwrap(), "http://10.0.0.8:8000/rsu/rsu-0001/");
+        assert_eq!(identity().expand("no variables, {} and $ stay").unwrap(), "no variables, {} and $ stay");
+    }
+
+    #[test]
+    fn expand_rejects_unknown_variables() {
+        let e = identity().expand("/rsu/${RSU_NAME}/status").unwrap_err();
+        assert!(e.contains("${RSU_NAME}"), "{}", e);
+        assert!(identity().expand("${}").is_err());
+    }
+
+    #[test]
+    fn expand_rejects_unterminated_variables() {
+        assert!(identity().expand("/rsu/${RSU_ID").is_err());
+        assert!(identity().expand("${RSU_ID}/${").is_err());
+    }
+
+    #[test]
+    fn expand_yaml_only_touches_string_values() {
+        let doc = &YamlLoader::load_from_str("\
+# ${COMMENT} is not expanded
+url: 'http://${HOST_IP}:8000/'
+peers: ['tcp/${HOST_IP}:7447', 7447]
+'${KEY}': 1
+zenoh:
+  prefix: /rsu/${RSU_ID}
+").unwrap()[0];
+        let doc = identity().expand_yaml(doc).unwrap();
+        assert_eq!(doc["url"].as_str(), Some("http://10.0.0.8:8000/"));
+        assert_eq!(doc["peers"][0].as_str(), Some("tcp/10.0.0.8:7447"));
+        assert_eq!(doc["peers"][1].as_i64(), Some(7447));
+        assert_eq!(doc["${KEY}"].as_i64(), Some(1));
+        assert_eq!(doc["zenoh"]["prefix"].as_str(), Some("/rsu/rsu-0001"));
+    }
+
+    #[test]
+    fn expanded_values_are_not_parsed_as_yaml() {
+        let identity = Identity {rsu_id: String::from("a: b"), ..identity()};
+        let doc = &YamlLoader::load_from_str("id: ${RSU_ID}\n").unwrap()[0];
+        let doc = identity.expand_yaml(doc).unwrap();
+        assert_eq!(doc["id"].as_str(), Some("a: b"));
+        let doc = &YamlLoader::load_from_str("zenoh:\n  prefix: /rsu/${RSU_ID\n").unwrap()[0];
+        let e = identity.expand_yaml(doc).unwrap_err();
+        assert!(e.starts_with("zenoh: prefix: "), "{}", e);
+    }
+}
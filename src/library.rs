@@ -0,0 +1,153 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, info, warn};
+use crate::signing::SignaturePolicy;
+
+
+// Where plugin libraries may be loaded from and the signature check they have to pass.
+// Only files inside the plugin dirs are loaded, after symlinks are resolved. The checked bytes are
+// copied into a private dir and loaded from there, so the file can not be swapped after the check.
+#[derive(Debug)]
+pub struct LibraryPolicy {
+    dirs: Vec<PathBuf>,
+    signing: SignaturePolicy,
+    // only accessible by the rsu user, removed with the policy
+    private_dir: PathBuf,
+    copies: AtomicUsize,
+}
+
+// A checked plugin library, the copy is removed when it is dropped
+#[derive(Debug)]
+pub struct CheckedLibrary {
+    // the canonical file in the plugin dirs
+    pub path: String,
+    // the private copy to load
+    pub copy: String,
+}
+
+impl Drop for CheckedLibrary {
+    fn drop(&mut self) {
+        // a loaded library stays mapped after its file is removed
+        let _ = fs::remove_file(&self.copy);
+    }
+}
+
+impl LibraryPolicy {
+    pub fn new(dirs: &[String], signing: SignaturePolicy) -> Result<LibraryPolicy, String> {
+        let mut canonical_dirs: Vec<PathBuf> = vec![];
+        for dir in dirs {
+            match fs::canonicalize(dir) {
+                Ok(d) if d.is_dir() => {
+                    info!("plugin libraries are loaded from {}", d.display());
+                    if !canonical_dirs.contains(&d) {
+                        canonical_dirs.push(d);
+                    }
+                },
+                Ok(_) => warn!("plugin dir {} is not a directory, ignored", dir),
+                Err(e) => warn!("plugin dir {} is ignored: {:?}", dir, e),
+            }
+        }
+        if canonical_dirs.is_empty() {
+            return Err(format!("none of the plugin dirs {:?} exists", dirs))
+        }
+        let private_dir = create_private_dir()?;
+        debug!("plugin libraries are loaded from copies in {}", private_dir.display());
+        Ok(LibraryPolicy {dirs: canonical_dirs, signing, private_dir, copies: AtomicUsize::new(0)})
+    }
+
+    // The canonical file `path` refers to. A bare file name is looked up in the plugin dirs in order,
+    // not in the search path of the dynamic linker.
+    pub fn resolve(&self, path: &str) -> Result<String, String> {
+        let bare = !path.contains('/');
+        let candidates: Vec<PathBuf> = if bare {
+            self.dirs.iter().map(|d| d.join(path)).collect()
+        } else {
+            vec![PathBuf::from(path)]
+        };
+        let file = candidates.iter().find_map(|p| fs::canonicalize(p).ok())
+                    .ok_or_else(|| if bare {
+                        format!("plugin library {} not found in plugin dirs {:?}", path, self.dirs)
+                    } else {
+                        format!("plugin library {} not found", path)
+                    })?;
+        if !self.dirs.iter().any(|d| file.starts_with(d)) {
+            return Err(format!("plugin library {} resolves to {}, which is outside the plugin dirs {:?}", path, file.display(), self.dirs))
+        }
+        if !file.is_file() {
+            return Err(format!("plugin library {} resolves to {}, which is not a file", path, file.display()))
+        }
+        file.to_str().map(String::from).ok_or(format!("plugin library path {} is not utf-8", file.display()))
+    }
+
+    // Resolve `path` and check the signature of the file, returns the copy of the checked bytes to load
+    pub fn check(&self, path: &str) -> Result<CheckedLibrary, String> {
+        let file = self.resolve(path)?;
+        let data = fs::read(&file).map_err(|e| format!("read plugin library {} failed: {:?}", file, e))?;
+        self.signing.verify(&file, &data)?;
+
+        let seq = self.copies.fetch_add(1, Ordering::SeqCst);
+        let name = Path::new(&file).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let copy = self.private_dir.join(format!("{}-{}", seq, name)).to_string_lossy().into_owned();
+        let library = CheckedLibrary {path: file, copy};
+        let mut out = OpenOptions::new().write(true).create_new(true).mode(0o700).open(&library.copy)
+                        .map_err(|e| format!("copy plugin library to {} failed: {:?}", library.copy, e))?;
+        out.write_all(&data).map_err(|e| format!("copy plugin library to {} failed: {:?}", library.copy, e))?;
+        Ok(library)
+    }
+}
+
+impl Drop for LibraryPolicy {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.private_dir);
+    }
+}
+
+// A new dir only the rsu user can access, its name is random so nobody can create it in advance
+fn create_private_dir() -> Result<PathBuf, String> {
+    let random = RandomState::new().build_hasher().finish();
+    let dir = env::temp_dir().join(format!("rsu-{}-{:016x}", std::process::id(), random));
+    DirBuilder::new().mode(0o700).create(&dir)
+        .map_err(|e| format!("create private plugin dir {} failed: {:?}", dir.display(), e))?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use yaml_rust::Yaml;
+
+    #[test]
+    fn checked_library_is_a_private_copy() {
+        let dir = env::temp_dir().join(format!("rsu-test-library-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("liba.so"), "checked").unwrap();
+        let policy = LibraryPolicy::new(&[dir.to_string_lossy().into_owned()], SignaturePolicy::from_yaml(&Yaml::BadValue).unwrap()).unwrap();
+        assert_eq!(fs::metadata(&policy.private_dir).unwrap().permissions().mode() & 0o777, 0o700);
+
+        let library = policy.check("liba.so").unwrap();
+        assert_eq!(library.path, fs::canonicalize(dir.join("liba.so")).unwrap().to_string_lossy());
+        assert!(library.copy.starts_with(&*policy.private_dir.to_string_lossy()));
+        // replacing the file after the check does not change what is loaded
+        fs::write(dir.join("liba.so"), "swapped").unwrap();
+        assert_eq!(fs::read_to_string(&library.copy).unwrap(), "checked");
+        // every check gets its own copy
+        let again = policy.check("liba.so").unwrap();
+        assert_ne!(again.copy, library.copy);
+
+        let copy = library.copy.clone();
+        drop(library);
+        assert!(!Path::new(&copy).exists());
+        let private_dir = policy.private_dir.clone();
+        drop(again);
+        drop(policy);
+        assert!(!private_dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,15 +2,62 @@ use std::error::Error;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 use log::{info, error};
 extern crate yaml_rust;
-use yaml_rust::{YamlLoader, YamlEmitter};
+use yaml_rust::{Yaml, YamlLoader, YamlEmitter};
 use tokio;
+mod api;
+mod auth;
 mod server;
-use server::{PM, server, send};
+use server::{PM, server, send, stop_all};
+use auth::Auth;
+mod package;
+use package::PluginStore;
 mod plugin;
+use plugin::PluginMgr;
+mod plugin_host;
+mod signing;
+use signing::SignaturePolicy;
+mod library;
+mod events;
+mod metrics;
+use library::LibraryPolicy;
+mod uplink;
+use uplink::TransportCfg;
+mod identity;
+use identity::Identity;
+mod command;
+use command::CommandCfg;
+mod zenoh_api;
+use zenoh_api::ZenohApiCfg;
+use rsu_outbox::OutboxConfig;
 
-fn read_rsu_cfg(path: &str) -> Result<(String, String, u64), Box<dyn Error>>{
+const DEFAULT_PLUGIN_DIR: &str = "./lib/plugins";
+const DEFAULT_REPORT_TIMEOUT: u64 = 5;
+
+// rsu.yaml
+struct RsuCfg {
+    // rsu_id, HOST_IP and location, the variables of rsu.yaml and the plugin configs
+    identity: Identity,
+    port: String,
+    report_duration: u64,
+    // how reports reach the center
+    uplink: TransportCfg,
+    // reports are queued on disk until they are delivered
+    outbox: OutboxConfig,
+    // the outbound command channel, None if the center only uses the API
+    command: Option<CommandCfg>,
+    // the plugin API over zenoh, None if it is not configured
+    zenoh_api: Option<ZenohApiCfg>,
+    auth: Auth,
+    // managed directory of uploaded plugin libraries
+    plugin_dir: String,
+    // where plugin libraries may be loaded from, and their signature check
+    library: LibraryPolicy,
+}
+
+fn read_rsu_cfg(path: &str) -> Result<RsuCfg, Box<dyn Error>>{
     if !Path::new(path).exists() {
         let mut dir_path_vec:Vec<&str> = path.split('/').collect();
         dir_path_vec.pop();
@@ -20,14 +67,63 @@ fn read_rsu_cfg(path: &str) -> Result<(String, String, u64), Box<dyn Error>>{
     }
     let config_str = fs::read_to_string(path)?;
     let config_docs = YamlLoader::load_from_str(config_str.as_str())?;
-    let config = &config_docs[0];
+    // the identity is read first, the string values of the file may use ${RSU_ID} and ${HOST_IP}
+    let identity = Identity::from_yaml(&config_docs[0])?;
+    let config = &identity.expand_yaml(&config_docs[0])?;
     let port = String::from(config["port"].as_str().ok_or("get port from cfg failed".to_owned())?);
-    let ip = env::var("HOST_IP").unwrap_or("127.0.0.1".to_string());
-    let center_db_url = String::from(config["center_db_url"].as_str().ok_or("get center_db_url from cfg failed".to_owned())?)
-    .replace("127.0.0.1", &ip);
-    let send_duration: u64 = config["report_duration"].as_i64().ok_or("get center_db_url from cfg failed".to_owned())? as u64;
+    // only needed by the http uplink without `uplink.http.url`
+    let center_db_url = String::from(config["center_db_url"].as_str().unwrap_or(""));
+    let send_duration = read_positive(config, "report_duration", "report_duration")?.ok_or("get report_duration from cfg failed")?;
+    // 0 would time out every delivery before it is sent
+    let report_timeout = read_positive(config, "report_timeout", "report_timeout")?.unwrap_or(DEFAULT_REPORT_TIMEOUT);
+    let uplink = TransportCfg::from_yaml(&config["uplink"], &center_db_url, &identity, Duration::from_secs(report_timeout))?;
+    let outbox = read_outbox_cfg(&config["outbox"])?;
+    let command = CommandCfg::from_yaml(&config["command"])?;
+    let zenoh_api = ZenohApiCfg::from_yaml(&config["zenoh_api"], &identity)?;
+    let auth = Auth::from_yaml(&config["auth"])?;
+    if zenoh_api.is_some() && !auth.has_tokens() {
+        return Err(Box::from("zenoh_api needs auth.tokens, every request over zenoh has to be signed with a token"))
+    }
+    let plugin_dir = String::from(config["plugin_dir"].as_str().unwrap_or(DEFAULT_PLUGIN_DIR));
+    // the managed dir is always allowed, uploaded libraries are stored there
+    fs::create_dir_all(&plugin_dir)?;
+    let mut plugin_dirs = vec![plugin_dir.clone()];
+    if let Some(dirs) = config["plugin_dirs"].as_vec() {
+        for dir in dirs {
+            plugin_dirs.push(String::from(dir.as_str().ok_or("read plugin_dirs failed")?));
+        }
+    }
+    let signing = SignaturePolicy::from_yaml(&config["signing"])?;
+    let library = LibraryPolicy::new(&plugin_dirs, signing)?;
+
+    Ok(RsuCfg {identity, port, report_duration: send_duration, uplink, outbox, command, zenoh_api, auth, plugin_dir, library})
+}
 
-    Ok((port, center_db_url, send_duration))
+fn read_outbox_cfg(cfg: &Yaml) -> Result<OutboxConfig, Box<dyn Error>> {
+    let mut outbox = OutboxConfig::default();
+    if let Some(dir) = cfg["dir"].as_str() {
+        outbox.dir = String::from(dir);
+    }
+    if let Some(max_entries) = read_positive(cfg, "max_entries", "outbox max_entries")? {
+        outbox.max_entries = max_entries as usize;
+    }
+    if let Some(max_bytes) = read_positive(cfg, "max_bytes", "outbox max_bytes")? {
+        outbox.max_bytes = max_bytes;
+    }
+    // 0 would retry an unreachable center in a busy loop
+    if let Some(max_backoff) = read_positive(cfg, "max_backoff", "outbox max_backoff")? {
+        outbox.max_backoff = Duration::from_secs(max_backoff);
+    }
+    Ok(outbox)
+}
+
+// `name` is how errors refer to the field
+fn read_positive(cfg: &Yaml, key: &str, name: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    match &cfg[key] {
+        Yaml::BadValue => Ok(None),
+        Yaml::Integer(v) if *v > 0 => Ok(Some(*v as u64)),
+        v => Err(format!("{} must be an integer greater than 0, got {:?}", name, v).into()),
+    }
 }
 
 
@@ -50,26 +146,78 @@ report_duration: 1"###;
 #[tokio::main]
 async fn main() {
     env_logger::init();
+
+    // `rsu plugin-host <name> <path> <fd>` runs a single out-of-process plugin for the parent rsu
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "plugin-host" {
+        if args.len() != 5 {
+            error!("usage: rsu plugin-host <plugin name> <plugin path> <socket fd>");
+            std::process::exit(-1);
+        }
+        std::process::exit(plugin_host::run_plugin_host(&args[2], &args[3], &args[4]));
+    }
     
     let cfg_path = "./config/rsu.yaml";
-    let (port, center_db_url, send_duration) = match read_rsu_cfg(&cfg_path){
-        Ok((port, center_db_url, send_duration)) => (port, center_db_url, send_duration),
+    let rsu_cfg = match read_rsu_cfg(&cfg_path){
+        Ok(rsu_cfg) => rsu_cfg,
         Err(e) => {
             error!("start RSU failed, read config failed: {:?}", e);
             return
         }
     };
 
-    if center_db_url == "" {
-        error!("start RSU failed, center_db_url is empty");
+    info!("RSU {} starting", rsu_cfg.identity.rsu_id);
+    identity::init(rsu_cfg.identity.clone());
+
+    // plugins report as soon as they are started
+    let outbox_dir = rsu_cfg.outbox.dir.clone();
+    if let Err(e) = uplink::init(rsu_cfg.uplink, rsu_cfg.outbox).await {
+        error!("start RSU failed, connect uplink failed: {}", e);
         return
     }
 
-    {
-        let _pm = PM.lock().unwrap();
+    let plugins_cfg_path = "./config/plugins.yaml";
+    match PluginMgr::new(plugins_cfg_path, rsu_cfg.library) {
+        Ok(pm) => *PM.lock().unwrap() = Some(pm),
+        Err(e) => {
+            error!("start RSU failed, load plugins failed: {:?}", e);
+            return
+        }
     }
 
-    tokio::spawn(server(port));
-    
-    send(center_db_url, send_duration).await;
+    // queued ahead of the first plugin state report
+    let registration = {
+        let pm_locked = PM.lock().unwrap();
+        rsu_cfg.identity.registration(&rsu_cfg.port, pm_locked.as_ref().unwrap().plugin_views())
+    };
+    if let Err(e) = uplink::submit(uplink::HOST_QUEUE, uplink::REGISTER_TOPIC, registration) {
+        error!("queue RSU registration failed: {}", e);
+    }
+
+    if let Some(zenoh_api) = rsu_cfg.zenoh_api {
+        let (auth, identity) = (rsu_cfg.auth.clone(), rsu_cfg.identity.clone());
+        tokio::spawn(async move {
+            if let Err(e) = zenoh_api::run(zenoh_api, auth, identity).await {
+                error!("zenoh api stopped: {}", e);
+            }
+        });
+    }
+    tokio::spawn(server(rsu_cfg.port, rsu_cfg.auth, PluginStore::new(&rsu_cfg.plugin_dir), outbox_dir));
+    if let Some(command) = rsu_cfg.command {
+        tokio::spawn(command::run(command));
+    }
+
+    tokio::select! {
+        _ = send(rsu_cfg.report_duration) => {},
+        _ = tokio::signal::ctrl_c() => {
+            info!("RSU is shutting down");
+        },
+    };
+
+    // dependents are stopped before the plugins they depend on
+    if let Err(e) = tokio::task::spawn_blocking(stop_all).await {
+        error!("stop plugins failed: {:?}", e);
+    }
+    // removes the private copies of the plugin libraries
+    drop(PM.lock().unwrap().take());
 }
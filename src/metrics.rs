@@ -0,0 +1,140 @@
+use std::time::{Duration, Instant};
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Registry, TextEncoder};
+use prometheus::{histogram_opts, opts};
+use lazy_static::lazy_static;
+use tide::{Middleware, Next, Request};
+use tide::utils::async_trait;
+use crate::plugin::{DesiredState, PluginMgr};
+
+
+// every state a plugin can be in, each one is a series of rsu_plugin_state
+const PLUGIN_STATES: [&str; 8] = ["stopped", "starting", "running", "degraded", "failed", "restarting", "stopping", "stuck"];
+
+lazy_static! {
+    static ref REGISTRY: Registry = {
+        let registry = Registry::new();
+        registry.register(Box::new(PLUGIN_STATE.clone())).unwrap();
+        registry.register(Box::new(PLUGIN_DESIRED_RUNNING.clone())).unwrap();
+        registry.register(Box::new(PLUGIN_UPTIME.clone())).unwrap();
+        registry.register(Box::new(PLUGIN_RESTART_COUNT.clone())).unwrap();
+        registry.register(Box::new(PLUGIN_CRASH_LOOP.clone())).unwrap();
+        registry.register(Box::new(PLUGIN_RESTARTS.clone())).unwrap();
+        registry.register(Box::new(OUTBOX_DEPTH.clone())).unwrap();
+        registry.register(Box::new(OUTBOX_BYTES.clone())).unwrap();
+        registry.register(Box::new(REPORTS.clone())).unwrap();
+        registry.register(Box::new(REPORT_DURATION.clone())).unwrap();
+        registry.register(Box::new(REGISTERED.clone())).unwrap();
+        registry.register(Box::new(HTTP_REQUESTS.clone())).unwrap();
+        registry.register(Box::new(HTTP_REQUEST_DURATION.clone())).unwrap();
+        #[cfg(target_os = "linux")]
+        registry.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).unwrap();
+        registry
+    };
+
+    // refreshed from the plugin manager on every scrape
+    static ref PLUGIN_STATE: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_plugin_state", "1 for the state the plugin is in, 0 for the others"), &["plugin", "state"]).unwrap();
+    static ref PLUGIN_DESIRED_RUNNING: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_plugin_desired_running", "1 if the plugin should be running"), &["plugin"]).unwrap();
+    static ref PLUGIN_UPTIME: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_plugin_uptime_seconds", "seconds since the plugin was started, 0 if it is not running"), &["plugin"]).unwrap();
+    static ref PLUGIN_RESTART_COUNT: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_plugin_consecutive_restarts", "restarts since the plugin last ran stable"), &["plugin"]).unwrap();
+    static ref PLUGIN_CRASH_LOOP: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_plugin_crash_loop", "1 if the plugin gave up restarting after max_retries"), &["plugin"]).unwrap();
+    // refreshed from the outbox dir on every scrape, plugins queue in the same dir
+    static ref OUTBOX_DEPTH: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_outbox_depth", "uploads to the center db waiting in the outbox"), &["queue"]).unwrap();
+    static ref OUTBOX_BYTES: IntGaugeVec = IntGaugeVec::new(
+        opts!("rsu_outbox_bytes", "size of the uploads waiting in the outbox"), &["queue"]).unwrap();
+
+    static ref PLUGIN_RESTARTS: IntCounterVec = IntCounterVec::new(
+        opts!("rsu_plugin_restarts_total", "automatic restarts of a plugin by its restart policy"), &["plugin", "result"]).unwrap();
+    static ref REPORTS: IntCounterVec = IntCounterVec::new(
+        opts!("rsu_report_total", "attempts to deliver a plugin state report to the center db"), &["result"]).unwrap();
+    static ref REPORT_DURATION: HistogramVec = HistogramVec::new(
+        histogram_opts!("rsu_report_duration_seconds", "latency of plugin state reports to the center db"), &["result"]).unwrap();
+    static ref REGISTERED: IntGauge = IntGauge::new(
+        "rsu_registered", "1 once the registration of this start was delivered to the center").unwrap();
+    static ref HTTP_REQUESTS: IntCounterVec = IntCounterVec::new(
+        opts!("rsu_http_requests_total", "requests to the management API"), &["method", "path", "status"]).unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = HistogramVec::new(
+        histogram_opts!("rsu_http_request_duration_seconds", "latency of the management API"), &["method", "path"]).unwrap();
+}
+
+pub fn plugin_restarted(name: &str, ok: bool) {
+    PLUGIN_RESTARTS.with_label_values(&[name, if ok { "success" } else { "failure" }]).inc();
+}
+
+pub fn report_sent(ok: bool, elapsed: Duration) {
+    let result = if ok { "success" } else { "failure" };
+    REPORTS.with_label_values(&[result]).inc();
+    REPORT_DURATION.with_label_values(&[result]).observe(elapsed.as_secs_f64());
+}
+
+pub fn set_registered(registered: bool) {
+    REGISTERED.set(registered as i64);
+}
+
+// Prometheus text exposition of all metrics, plugin gauges are taken from `pm`
+// and outbox gauges from the queues under `outbox_dir`
+pub fn render(pm: &PluginMgr, outbox_dir: &str) -> Result<String, String> {
+    // removed plugins must not keep their series
+    PLUGIN_STATE.reset();
+    PLUGIN_DESIRED_RUNNING.reset();
+    PLUGIN_UPTIME.reset();
+    PLUGIN_RESTART_COUNT.reset();
+    PLUGIN_CRASH_LOOP.reset();
+    for view in pm.plugin_views() {
+        for state in PLUGIN_STATES.iter() {
+            let value = if *state == view.state.as_str() { 1 } else { 0 };
+            PLUGIN_STATE.with_label_values(&[&view.name, state]).set(value);
+        }
+        PLUGIN_DESIRED_RUNNING.with_label_values(&[&view.name]).set((view.desired == DesiredState::Running) as i64);
+        PLUGIN_UPTIME.with_label_values(&[&view.name]).set(view.uptime as i64);
+        PLUGIN_RESTART_COUNT.with_label_values(&[&view.name]).set(view.restart_count as i64);
+        PLUGIN_CRASH_LOOP.with_label_values(&[&view.name]).set(view.crash_loop as i64);
+    }
+    OUTBOX_DEPTH.reset();
+    OUTBOX_BYTES.reset();
+    for queue in rsu_outbox::queue_stats(outbox_dir) {
+        OUTBOX_DEPTH.with_label_values(&[&queue.name]).set(queue.depth as i64);
+        OUTBOX_BYTES.with_label_values(&[&queue.name]).set(queue.bytes as i64);
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer).map_err(|e| format!("encode metrics failed: {:?}", e))?;
+    String::from_utf8(buffer).map_err(|e| format!("encode metrics failed: {:?}", e))
+}
+
+// Counts requests to the management API by route, plugin names are folded into `:name`
+pub struct HttpMetrics;
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HttpMetrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let method = req.method().to_string();
+        let path = route_of(req.url().path());
+        let start = Instant::now();
+        let res = next.run(req).await;
+        HTTP_REQUESTS.with_label_values(&[&method, path, &(res.status() as u16).to_string()]).inc();
+        HTTP_REQUEST_DURATION.with_label_values(&[&method, path]).observe(start.elapsed().as_secs_f64());
+        Ok(res)
+    }
+}
+
+fn route_of(path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/plugins" => "/plugins",
+        "/plugin" => "/plugin",
+        "/plugin/remove" => "/plugin/remove",
+        "/plugin/reload" => "/plugin/reload",
+        "/plugin/add" => "/plugin/add",
+        "/plugin/upload" => "/plugin/upload",
+        "/events" => "/events",
+        "/metrics" => "/metrics",
+        p if p.starts_with("/plugin/") => "/plugin/:name",
+        _ => "other",
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+use log::{info, debug};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::plugin::PluginError;
+
+
+// Managed directory of uploaded plugin libraries, laid out as `<dir>/<name>/<version>/lib<name>.so`
+#[derive(Debug)]
+pub struct PluginStore {
+    dir: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StoredLibrary {
+    pub path: String,
+    pub version: String,
+    pub sha256: String,
+}
+
+impl PluginStore {
+    pub fn new(dir: &str) -> PluginStore {
+        PluginStore {dir: String::from(dir.trim_end_matches('/'))}
+    }
+
+    // Verify `data` against the sha256 supplied by the caller and store it as version `version`,
+    // the version defaults to the first 12 digits of the checksum. A detached signature is stored
+    // next to the library, it is checked when the library is loaded.
+    pub fn store(&self, name: &str, version: Option<&str>, data: &[u8], sha256: &str,
+                 signature: Option<&str>) -> Result<StoredLibrary, PluginError> {
+        check_component("plugin name", name)?;
+        let digest = sha256_hex(data);
+        let expected = sha256.trim().to_lowercase();
+        if digest != expected {
+            return Err(PluginError::Invalid(format!("checksum mismatch for plugin[{}]: expected {}, got {}", name, expected, digest)))
+        }
+        let version = String::from(version.unwrap_or(&digest[..12]));
+        check_component("plugin version", &version)?;
+
+        let version_dir = format!("{}/{}/{}", self.dir, name, version);
+        let path = format!("{}/lib{}.so", version_dir, name);
+        if Path::new(&path).exists() {
+            let stored = fs::read(&path).map_err(|e| format!("read stored library {} failed: {:?}", path, e))?;
+            if sha256_hex(&stored) != digest {
+                return Err(PluginError::Conflict(format!("plugin[{}] version {} is already stored with a different checksum", name, version)))
+            }
+            debug!("plugin[{}] version {} is already stored", name, version);
+            store_signature(&path, signature)?;
+            return Ok(StoredLibrary {path, version, sha256: digest})
+        }
+
+        fs::create_dir_all(&version_dir).map_err(|e| format!("create plugin dir {} failed: {:?}", version_dir, e))?;
+        store_signature(&path, signature)?;
+        // a library is never visible half written
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, data).map_err(|e| format!("write plugin library {} failed: {:?}", tmp_path, e))?;
+        fs::rename(&tmp_path, &path).map_err(|e| format!("move plugin library to {} failed: {:?}", path, e))?;
+        info!("plugin[{}] version {} stored at {}", name, version, path);
+        Ok(StoredLibrary {path, version, sha256: digest})
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn store_signature(path: &str, signature: Option<&str>) -> Result<(), PluginError> {
+    if let Some(signature) = signature {
+        let sig_path = format!("{}.sig", path);
+        fs::write(&sig_path, signature.trim()).map_err(|e| format!("write plugin signature {} failed: {:?}", sig_path, e))?;
+    }
+    Ok(())
+}
+
+// names and versions become path components, keep them from escaping the store
+fn check_component(what: &str, value: &str) -> Result<(), PluginError> {
+    let valid = !value.is_empty()
+                && value != "." && value != ".."
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        return Err(PluginError::Invalid(format!("{} {:?} may only contain letters, digits, '_', '-' and '.'", what, value)))
+    }
+    Ok(())
+}
@@ -6,132 +6,833 @@ use std::thread;
 use std::thread::JoinHandle;
 use libloading::Library;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ffi::CStr;
+use std::os::raw::c_void;
 extern crate yaml_rust;
 use yaml_rust::{YamlLoader, YamlEmitter, Yaml};
 use linked_hash_map::LinkedHashMap;
 use serde::{Deserialize, Serialize};
+use crate::plugin_host::ProcessPlugin;
+use crate::library::LibraryPolicy;
+use crate::events::{self, EventKind};
+use crate::metrics;
+use crate::uplink;
+use crate::identity;
+use rsu_plugin_abi::{AbiVersionFn, EntryFn, RunFn, HostContext, PluginFlags, RSU_PLUGIN_ABI_VERSION, ABI_VERSION_SYMBOL, ENTRY_SYMBOL};
 
 
-type PluginFunc = unsafe extern fn(running_flag: Arc<Mutex<bool>>, error_flag: Arc<Mutex<bool>>) -> i32;
-
+// Plugin library loaded into the rsu process, `run` is called on a dedicated thread
 #[derive(Debug)]
-pub struct Plugin {
-    lib_handle: Arc<Mutex<Library>>,
+pub struct LocalPlugin {
+    path: String,
+    // name the manager knows the plugin by, its reports are queued under it
+    name: String,
+    lib_handle: Arc<Library>,
+    run_func: RunFn,
     thread_handle: Option<JoinHandle<Result<i32, String>>>,
-    running_flag: Arc<Mutex<bool>>,
-    error_flag: Arc<Mutex<bool>>,
+    flags: Arc<PluginFlags>,
+    // set by the plugin thread once `run` has returned
+    exited: Arc<AtomicBool>,
+    started_at: Option<Instant>,
 }
 
-impl Plugin {
-    pub fn new(path: &str) -> Result<Plugin, String> {
+impl LocalPlugin {
+    // Load the library `file`, a checked copy of `path`
+    pub fn new(name: &str, path: &str, file: &str) -> Result<LocalPlugin, String> {
         unsafe {
-            match Library::new(path) {
-                Ok(lib) => {
-                    let lib_handle = Arc::new(Mutex::new(lib));
-                    Ok(Plugin {
-                        lib_handle,
-                        running_flag: Arc::new(Mutex::new(true)),
-                        thread_handle: None,
-                        error_flag: Arc::new(Mutex::new(false))
-                    })
-                },
-                Err(error) => {
-                    Err(format!("Problem opening the file: {:?}", error))
-                },
+            let lib = Library::new(file).map_err(|e| format!("Problem opening the file: {:?}", e))?;
+
+            // check the ABI version before resolving anything else from the library
+            let abi_version = match lib.get::<AbiVersionFn>(ABI_VERSION_SYMBOL) {
+                Ok(func) => func(),
+                Err(_) => {
+                    error!("plugin[{}] does not export rsu_plugin_abi_version", path);
+                    return Err(format!("plugin[{}] is incompatible: it does not export rsu_plugin_abi_version, \
+                                        rebuild it against rsu-plugin-abi v{}", path, RSU_PLUGIN_ABI_VERSION))
+                }
+            };
+            if abi_version != RSU_PLUGIN_ABI_VERSION {
+                error!("plugin[{}] abi version {} does not match host abi version {}", path, abi_version, RSU_PLUGIN_ABI_VERSION);
+                return Err(format!("plugin[{}] is incompatible: built against plugin ABI v{}, host requires v{}",
+                                   path, abi_version, RSU_PLUGIN_ABI_VERSION))
+            }
+
+            let entry_func = lib.get::<EntryFn>(ENTRY_SYMBOL).map_err(|e| {
+                error!("get lib fun[rsu_plugin_entry] failed: {:?}", e);
+                format!("plugin[{}] is incompatible: it does not export rsu_plugin_entry", path)
+            })?;
+            let entry = entry_func();
+            if entry.is_null() {
+                return Err(format!("plugin[{}] returned an empty entry table", path))
+            }
+            if (*entry).abi_version != RSU_PLUGIN_ABI_VERSION {
+                return Err(format!("plugin[{}] is incompatible: entry table is ABI v{}, host requires v{}",
+                                   path, (*entry).abi_version, RSU_PLUGIN_ABI_VERSION))
+            }
+            let run_func = (*entry).run;
+            if (*entry).name.is_null() {
+                return Err(format!("plugin[{}] entry table has no name", path))
             }
+            debug!("plugin[{}] library {} declares the name {}", name, path, CStr::from_ptr((*entry).name).to_string_lossy());
+
+            Ok(LocalPlugin {
+                path: String::from(path),
+                name: String::from(name),
+                lib_handle: Arc::new(lib),
+                run_func,
+                thread_handle: None,
+                flags: Arc::new(PluginFlags::new()),
+                exited: Arc::new(AtomicBool::new(false)),
+                started_at: None,
+            })
         }
     }
 
-    fn start(&mut self) -> Result<(), String>{
+    // `config` is the yaml config document handed to the plugin
+    pub(crate) fn start(&mut self, config: &str) -> Result<(), String>{
+        // the thread keeps the library mapped while the plugin is running
         let lib = Arc::clone(&self.lib_handle);
-        let flag = Arc::clone(&self.running_flag);
-        let error_flag = Arc::clone(&self.error_flag);
-
+        let flags = Arc::clone(&self.flags);
+        let exited = Arc::clone(&self.exited);
+        let func = self.run_func;
+        let config = String::from(config);
+        let name = self.name.clone();
 
         let join_handle = thread::spawn(move || -> Result<i32, String> {
-            unsafe {
-                let h = lib.lock().map_err(|e|
-                    {
-                        error!("lock lib failed: {:?}", e);
-                        format!("lock lib failed: {:?}", e)
-                    })?;
-
-                let func = h.get::<PluginFunc>(b"run").map_err(|e|
-                    {
-                        error!("get lib fun[run]failed: {:?}", e);
-                        format!("get lib fun[run]failed: {:?}", e)
-                    })?;
-                
-                let ret = func(flag, error_flag);
-                debug!("plugin func ret: {:?}", ret);
-                if ret < 0 {
-                    error!("start plugin failed: {:?}", ret);
-                    return Err(format!("start plugin failed: {:?}", ret))
-                }
-
-                Ok(ret)
+            let _lib = lib;
+            // marks the plugin exited even if `run` unwinds
+            let _exited = ExitGuard(exited);
+            // flags, config and name outlive `run`, they are owned by this thread
+            let host = HostContext {
+                flags: &*flags as *const PluginFlags,
+                config: config.as_ptr(),
+                config_len: config.len(),
+                report: uplink::report_from_plugin,
+                host_data: &name as *const String as *const c_void,
+            };
+            let ret = unsafe { func(&host as *const HostContext) };
+            debug!("plugin func ret: {:?}", ret);
+            if flags.keep_loaded() {
+                // code of the plugin may still run on threads it does not own, never unload the library
+                info!("plugin asked to stay loaded after stop");
+                std::mem::forget(_lib);
             }
+            if ret < 0 {
+                error!("start plugin failed: {:?}", ret);
+                return Err(format!("start plugin failed: {:?}", ret))
+            }
+
+            Ok(ret)
         });
         
         self.thread_handle = Some(join_handle);
+        self.started_at = Some(Instant::now());
         Ok(())
     }
 
-    fn stop(&mut self) -> Result<i32, String> {
-        {
-            let mut the_flag = self.running_flag.lock().map_err(|e| {
-                error!("stop plugin failed: {:?}", e);
-                format!("stop plugin failed: {:?}", e)
-            })?;
-            *the_flag = false;
+    // Ask the plugin to stop and wait at most `timeout` for `run` to return.
+    // A stuck plugin keeps its thread and library, it can be stopped again later.
+    pub(crate) fn stop(&mut self, timeout: Duration) -> Result<i32, StopError> {
+        self.flags.stop();
+
+        let deadline = Instant::now() + timeout;
+        while !self.exited() {
+            if Instant::now() >= deadline {
+                error!("plugin did not stop within {:?}", timeout);
+                return Err(StopError::Stuck(timeout))
+            }
+            thread::sleep(Duration::from_millis(50));
         }
 
         if let Some(handle) = self.thread_handle.take() {
             match handle.join() {
                 Ok(ret) => {
                     debug!("handle join ret {:?}", ret);
-                    ret
+                    ret.map_err(StopError::Failed)
                 },
                 Err(e) => {
                     error!("handle join failed: {:?}", e);
-                    Err(format!("stop plugin failed: {:?}", e))
+                    Err(StopError::Failed(format!("stop plugin failed: {:?}", e)))
                 }
             }
 
         } else {
             error!("take plguin handle return None");
-            return Err(String::from("take plguin handle return None")) 
+            return Err(StopError::Failed(String::from("take plguin handle return None")))
+        }
+    }
+
+    pub(crate) fn check(&mut self)-> Result<(), String> {
+        if self.flags.has_error() {
+            error!("running plugin error");
+            return Err(format!("running plugin error"))
         }
+        
+        Ok(())
     }
 
-    fn check(&mut self)-> Result<(), String> {
-        let error_flag = Arc::clone(&self.error_flag);
+    pub(crate) fn exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
 
-        match error_flag.lock(){
-            Ok(flag) => {
-                if *flag {
-                    error!("running plugin error");
-                    return Err(format!("running plugin error"))
-                }
+    fn uptime(&self) -> Duration {
+        self.started_at.map(|t| t.elapsed()).unwrap_or_default()
+    }
+}
+
+struct ExitGuard(Arc<AtomicBool>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub enum StopError {
+    // the plugin stopped, but reported an error
+    Failed(String),
+    // the plugin did not stop before the deadline
+    Stuck(Duration),
+}
+
+impl fmt::Display for StopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopError::Failed(e) => write!(f, "{}", e),
+            StopError::Stuck(timeout) => write!(f, "plugin did not stop within {:?}", timeout),
+        }
+    }
+}
+
+impl From<StopError> for String {
+    fn from(e: StopError) -> String {
+        e.to_string()
+    }
+}
+
+// Error of a plugin management operation, the kind tells the API which status to answer with
+#[derive(Debug)]
+pub enum PluginError {
+    // the request itself is wrong, e.g. a dependency that does not exist
+    Invalid(String),
+    NotFound(String),
+    // the plugin is in a state that does not allow the operation
+    Conflict(String),
+    Failed(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginError::Invalid(e) | PluginError::NotFound(e) | PluginError::Conflict(e) | PluginError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for PluginError {
+    fn from(e: String) -> PluginError {
+        PluginError::Failed(e)
+    }
+}
+
+impl From<PluginError> for String {
+    fn from(e: PluginError) -> String {
+        e.to_string()
+    }
+}
+
+
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Isolation {
+    // load the library into the rsu process
+    InProcess,
+    // load the library in a `rsu plugin-host` child process
+    Process,
+}
+
+impl Isolation {
+    fn parse(isolation: &str) -> Result<Isolation, String> {
+        match isolation {
+            "in-process" => Ok(Isolation::InProcess),
+            "process" => Ok(Isolation::Process),
+            _ => Err(format!("unknown isolation: {}, expect in-process/process", isolation)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Isolation::InProcess => "in-process",
+            Isolation::Process => "process",
+        }
+    }
+}
+
+// A plugin managed by `PluginMgr`, whichever process it runs in
+#[derive(Debug)]
+pub enum Plugin {
+    InProcess(LocalPlugin),
+    Process(ProcessPlugin),
+}
+
+impl Plugin {
+    // `path` is resolved inside the plugin dirs and has to pass the signature check, either process
+    // loads the private copy of the checked bytes
+    pub fn new(name: &str, path: &str, isolation: Isolation, library: &LibraryPolicy) -> Result<Plugin, String> {
+        let library = library.check(path)?;
+        info!("load plugin library {} from {}", path, library.path);
+        match isolation {
+            Isolation::InProcess => Ok(Plugin::InProcess(LocalPlugin::new(name, &library.path, &library.copy)?)),
+            Isolation::Process => Ok(Plugin::Process(ProcessPlugin::new(name, library)?)),
+        }
+    }
+
+    // the canonical file the library was loaded from
+    fn path(&self) -> &str {
+        match self {
+            Plugin::InProcess(p) => &p.path,
+            Plugin::Process(p) => p.path(),
+        }
+    }
+
+    fn start(&mut self, config: &str) -> Result<(), String> {
+        match self {
+            Plugin::InProcess(p) => p.start(config),
+            Plugin::Process(p) => p.start(config),
+        }
+    }
+
+    pub fn stop(&mut self, timeout: Duration) -> Result<i32, StopError> {
+        match self {
+            Plugin::InProcess(p) => p.stop(timeout),
+            Plugin::Process(p) => p.stop(timeout),
+        }
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        match self {
+            Plugin::InProcess(p) => p.check(),
+            Plugin::Process(p) => p.check(),
+        }
+    }
+
+    fn exited(&self) -> bool {
+        match self {
+            Plugin::InProcess(p) => p.exited(),
+            Plugin::Process(p) => p.exited(),
+        }
+    }
+
+    fn uptime(&self) -> Duration {
+        match self {
+            Plugin::InProcess(p) => p.uptime(),
+            Plugin::Process(p) => p.uptime(),
+        }
+    }
+}
+
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_BACKOFF: u64 = 1;
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+const MAX_BACKOFF: u64 = 300;
+// a plugin running this long without failing gets its retry count reset
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const RESTART_HISTORY_LEN: usize = 10;
+
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    fn parse(policy: &str) -> Result<RestartPolicy, String> {
+        match policy {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!("unknown restart policy: {}, expect never/on-failure/always", policy)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RestartPolicy::Never => "never",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+// What the operator asked for, changed only through the API or plugins.yaml
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DesiredState {
+    Running,
+    Stopped,
+}
+
+impl DesiredState {
+    fn parse(desired: &str) -> Result<DesiredState, String> {
+        match desired {
+            "running" => Ok(DesiredState::Running),
+            "stopped" => Ok(DesiredState::Stopped),
+            _ => Err(format!("unknown desired state: {}, expect running/stopped", desired)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            DesiredState::Running => "running",
+            DesiredState::Stopped => "stopped",
+        }
+    }
+}
+
+// What the plugin is actually doing
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+pub enum PluginState {
+    Stopped,
+    Starting,
+    Running,
+    // running, but a plugin it depends on is not
+    Degraded { reason: String },
+    // `since` is a unix timestamp in seconds
+    Failed { reason: String, since: u64 },
+    // waiting for the restart backoff
+    Restarting,
+    Stopping,
+    // did not stop within stop_timeout, its thread or child process is still alive
+    Stuck,
+}
+
+impl PluginState {
+    fn failed(reason: &str) -> PluginState {
+        PluginState::Failed {reason: String::from(reason), since: unix_now()}
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PluginState::Stopped => "stopped",
+            PluginState::Starting => "starting",
+            PluginState::Running => "running",
+            PluginState::Degraded {..} => "degraded",
+            PluginState::Failed {..} => "failed",
+            PluginState::Restarting => "restarting",
+            PluginState::Stopping => "stopping",
+            PluginState::Stuck => "stuck",
+        }
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut state_map: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
+        state_map.insert(Yaml::from_str("name"), Yaml::from_str(self.as_str()));
+        match self {
+            PluginState::Degraded {reason} => {
+                state_map.insert(Yaml::from_str("reason"), Yaml::String(reason.clone()));
             },
-            Err(e) => {
-                debug!("lock error_flag failed: {:?}", e);
-                return Err(format!("start plugin failed: {:?}", e))
+            PluginState::Failed {reason, since} => {
+                state_map.insert(Yaml::from_str("reason"), Yaml::String(reason.clone()));
+                state_map.insert(Yaml::from_str("since"), Yaml::Integer(*since as i64));
             },
-        };
-        
-        Ok(())
+            _ => {},
+        }
+        Yaml::Hash(state_map)
+    }
+}
+
+impl fmt::Display for PluginState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginState::Degraded {reason} | PluginState::Failed {reason, ..} => write!(f, "{}({})", self.as_str(), reason),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl Default for PluginState {
+    fn default() -> Self {
+        PluginState::Stopped
     }
 }
 
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone)]
+pub struct RestartRecord {
+    // unix timestamp in seconds
+    time: u64,
+    reason: String,
+    restarted: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+#[derive(Debug, Clone, Default)]
+pub struct RestartStatus {
+    count: u32,
+    crash_loop: bool,
+    history: Vec<RestartRecord>,
+    #[serde(skip)]
+    next_restart: Option<Instant>,
+}
+
 
 #[derive(Deserialize, Serialize)]
 #[derive(Debug)]
 pub struct PluginInfo {
     path: String,
-    active: bool
+    desired: DesiredState,
+    // recomputed from the running plugins, persisted so the last known state survives in plugins.yaml
+    #[serde(skip_deserializing)]
+    state: PluginState,
+    isolation: Isolation,
+    restart: RestartPolicy,
+    max_retries: u32,
+    // initial restart delay in seconds, doubled on every retry
+    backoff: u64,
+    // seconds to wait for the plugin to stop before it is reported stuck
+    stop_timeout: u64,
+    // plugins that have to be running before this one starts
+    depends_on: Vec<String>,
+    // yaml file with the plugin config, relative paths are resolved against the directory of plugins.yaml
+    config_path: Option<String>,
+    // config document given inline in plugins.yaml, takes precedence over config_path
+    #[serde(skip)]
+    config_inline: Option<Yaml>,
+    // the config document handed to the plugin at its last start
+    #[serde(skip_deserializing)]
+    config: serde_json::Value,
+    #[serde(skip_deserializing)]
+    config_error: Option<String>,
+    #[serde(skip_deserializing)]
+    restarts: RestartStatus,
+    // the plugin is being stopped outside the manager lock
+    #[serde(skip_deserializing)]
+    stopping: bool,
+    // the plugin did not stop before stop_timeout, its thread and library are still alive
+    #[serde(skip_deserializing)]
+    stuck: bool,
+}
+
+impl PluginInfo {
+    fn new(path: &str, desired: DesiredState) -> PluginInfo {
+        PluginInfo {
+            path: String::from(path),
+            desired,
+            state: PluginState::Stopped,
+            isolation: Isolation::InProcess,
+            restart: RestartPolicy::Never,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            depends_on: vec![],
+            config_path: None,
+            config_inline: None,
+            config: serde_json::Value::Null,
+            config_error: None,
+            restarts: RestartStatus::default(),
+            stopping: false,
+            stuck: false,
+        }
+    }
+
+    // record a plugin exit and decide whether it should be restarted
+    fn schedule_restart(&mut self, failed: bool, reason: &str) -> bool {
+        let wanted = match self.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        let restart = wanted && self.restarts.count < self.max_retries;
+        if wanted && !restart {
+            self.restarts.crash_loop = true;
+        }
+
+        self.restarts.history.push(RestartRecord {time: unix_now(), reason: String::from(reason), restarted: restart});
+        if self.restarts.history.len() > RESTART_HISTORY_LEN {
+            self.restarts.history.remove(0);
+        }
+
+        if restart {
+            let delay = self.backoff.saturating_mul(1 << self.restarts.count.min(16)).min(MAX_BACKOFF);
+            self.restarts.count += 1;
+            self.restarts.next_restart = Some(Instant::now() + Duration::from_secs(delay));
+        } else {
+            self.restarts.next_restart = None;
+        }
+        restart
+    }
+
+    // returns true if the state changed
+    fn set_state(&mut self, name: &str, state: PluginState) -> bool {
+        if self.state == state {
+            return false
+        }
+        info!("plugin[{}] state {} -> {}", name, self.state, state);
+        events::publish(EventKind::State {plugin: String::from(name), from: self.state.clone(), to: state.clone()});
+        self.state = state;
+        true
+    }
+
+    fn restart_due(&self) -> bool {
+        match self.restarts.next_restart {
+            Some(at) => Instant::now() >= at,
+            None => false,
+        }
+    }
+
+    fn stop_timeout(&self) -> Duration {
+        Duration::from_secs(self.stop_timeout)
+    }
+
+    // Resolve and validate the config document the plugin is started with, the result is kept for reporting.
+    // Without a config entry `<config dir>/plugins/<name>.yaml` is used if it exists, otherwise the
+    // plugin gets an empty document and runs with its defaults.
+    fn load_config(&mut self, name: &str, cfg_dir: &str) -> Result<String, String> {
+        let ret = resolve_config(name, cfg_dir, self.config_path.as_deref(), self.config_inline.as_ref());
+        match ret {
+            Ok((text, doc)) => {
+                let config = yaml_to_json(&doc);
+                if config != self.config {
+                    events::publish(EventKind::Config {plugin: String::from(name), config: config.clone()});
+                }
+                self.config = config;
+                self.config_error = None;
+                Ok(text)
+            },
+            Err(e) => {
+                error!("{}", e);
+                self.config = serde_json::Value::Null;
+                self.config_error = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    fn reset_restarts(&mut self) {
+        self.restarts.count = 0;
+        self.restarts.crash_loop = false;
+        self.restarts.next_restart = None;
+    }
+
+    // settings that differ from `other`, the path, desired and runtime state are not compared
+    fn changed_settings(&self, other: &PluginInfo) -> Vec<&'static str> {
+        let mut fields: Vec<&'static str> = vec![];
+        if self.isolation != other.isolation {
+            fields.push("isolation");
+        }
+        if self.restart != other.restart {
+            fields.push("restart");
+        }
+        if self.max_retries != other.max_retries {
+            fields.push("max_retries");
+        }
+        if self.backoff != other.backoff {
+            fields.push("backoff");
+        }
+        if self.stop_timeout != other.stop_timeout {
+            fields.push("stop_timeout");
+        }
+        if self.depends_on != other.depends_on {
+            fields.push("depends_on");
+        }
+        if self.config_path != other.config_path || self.config_inline != other.config_inline {
+            fields.push("config");
+        }
+        fields
+    }
+
+    fn apply_settings(&mut self, other: &PluginInfo) {
+        self.isolation = other.isolation;
+        self.restart = other.restart;
+        self.max_retries = other.max_retries;
+        self.backoff = other.backoff;
+        self.stop_timeout = other.stop_timeout;
+        self.depends_on = other.depends_on.clone();
+        self.config_path = other.config_path.clone();
+        self.config_inline = other.config_inline.clone();
+    }
+}
+
+// Metadata of the library file of a plugin
+#[derive(Serialize, Debug)]
+pub struct LibraryInfo {
+    size: u64,
+    // unix timestamp in seconds
+    modified: u64,
+    // the library is mapped into rsu or a plugin-host child process
+    loaded: bool,
+}
+
+// Read-only view of a plugin returned by the query API
+#[derive(Serialize, Debug)]
+pub struct PluginView {
+    pub(crate) name: String,
+    path: String,
+    // canonical file `path` resolves to in the plugin dirs, the loaded one while the plugin runs
+    file: Option<String>,
+    isolation: Isolation,
+    pub(crate) desired: DesiredState,
+    pub(crate) state: PluginState,
+    // seconds since the plugin was started, 0 if it is not running
+    pub(crate) uptime: u64,
+    pub(crate) restart_count: u32,
+    pub(crate) crash_loop: bool,
+    last_error: Option<String>,
+    depends_on: Vec<String>,
+    library: Option<LibraryInfo>,
+    // only in the detail view
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restart_history: Option<Vec<RestartRecord>>,
+}
+
+// One step of bringing the plugins in line with a desired plugins document
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Change {
+    Remove { plugin: String },
+    Stop { plugin: String },
+    Add { plugin: String, path: String },
+    // a running plugin is reloaded from the new library, with rollback
+    Path { plugin: String, from: String, to: String },
+    Update { plugin: String, fields: Vec<&'static str> },
+    Start { plugin: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangeReport {
+    #[serde(flatten)]
+    change: Change,
+    // planned, applied or failed
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl ChangeReport {
+    pub(crate) fn planned(change: Change) -> ChangeReport {
+        ChangeReport {change, result: "planned", error: None}
+    }
+
+    pub(crate) fn applied(change: Change, ret: Result<(), PluginError>) -> ChangeReport {
+        match ret {
+            Ok(_) => ChangeReport {change, result: "applied", error: None},
+            Err(e) => ChangeReport {change, result: "failed", error: Some(e.to_string())},
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+// A plugin `detach_failed` found failed or exited. The caller stops it without holding the
+// manager lock and hands the result to `finish_failed`.
+#[derive(Debug)]
+pub struct FailedPlugin {
+    pub name: String,
+    pub plugin: Plugin,
+    pub timeout: Duration,
+    failed: bool,
+    reason: String,
+}
+
+// A reload `begin_reload` took out of the manager. `run` stops the old plugin and watches the new
+// build for its grace period without holding the manager lock, `finish_reload` records the outcome.
+#[derive(Debug)]
+pub struct ReloadJob {
+    name: String,
+    old_path: String,
+    new_path: String,
+    isolation: Isolation,
+    config: String,
+    grace: Duration,
+    stop_timeout: Duration,
+    // the running plugin, stopped before the new build is loaded
+    plugin: Option<Plugin>,
+    was_running: bool,
+    // copy of the last loaded build, see `snapshot_library`
+    backup: String,
+    library: Arc<LibraryPolicy>,
+}
+
+#[derive(Debug)]
+pub enum ReloadOutcome {
+    Reloaded(Plugin),
+    // the old plugin did not stop within its stop_timeout
+    Stuck(Plugin, Duration),
+    // the new build failed, the old one is back and running again if it was running
+    RolledBack(Option<Plugin>, String),
+    // the new build failed, and so did restoring the old one
+    RollbackFailed(String, String),
+}
+
+impl ReloadJob {
+    pub fn run(&mut self) -> ReloadOutcome {
+        // every load maps its own copy of the library, so a new build at the same path is loaded even when
+        // the old library asked to stay loaded
+        if let Some(mut plugin) = self.plugin.take() {
+            match plugin.stop(self.stop_timeout) {
+                Ok(_) => {},
+                Err(StopError::Failed(e)) => debug!("plugin[{}] stopped with error before reload: {}", self.name, e),
+                Err(StopError::Stuck(timeout)) => return ReloadOutcome::Stuck(plugin, timeout),
+            }
+        }
+        match start_with_grace(&self.name, &self.new_path, self.isolation, &self.library, &self.config, self.grace, self.stop_timeout) {
+            Ok(plugin) => ReloadOutcome::Reloaded(plugin),
+            Err(e) => {
+                error!("reload plugin[{}] from {} failed, roll back to {}: {}", self.name, self.new_path, self.old_path, e);
+                match self.roll_back() {
+                    Ok(plugin) => ReloadOutcome::RolledBack(plugin, e),
+                    Err(err) => ReloadOutcome::RollbackFailed(e, err),
+                }
+            }
+        }
+    }
+
+    // Put back the build that was loaded before a failed reload, and start it again if it was running
+    fn roll_back(&self) -> Result<Option<Plugin>, String> {
+        if let Ok(old_file) = self.library.resolve(&self.old_path) {
+            if self.library.resolve(&self.new_path).ok().as_ref() == Some(&old_file) {
+                // the new build was copied over the old one, put the last loaded build back
+                restore_library(&self.backup, &old_file)?;
+            }
+        }
+        if !self.was_running {
+            return Ok(None)
+        }
+        let mut plugin = Plugin::new(&self.name, &self.old_path, self.isolation, &self.library)?;
+        plugin.start(&self.config)?;
+        Ok(Some(plugin))
+    }
+}
+
+// A desired plugins document checked and planned by `plan_reconcile`. The caller applies the
+// changes one by one, so plugins are stopped without holding the manager lock.
+#[derive(Debug)]
+pub struct Reconcile {
+    wanted: HashMap<String, PluginInfo>,
+    order: Vec<String>,
+    pub changes: Vec<Change>,
 }
 
 #[derive(Debug)]
@@ -139,10 +840,14 @@ pub struct PluginMgr {
     config_path: String,
     pub plugin_cfg: HashMap<String, PluginInfo>,
     plugins: HashMap<String, Plugin>,
+    // plugins that did not stop in time, kept so their libraries stay loaded
+    stuck: Vec<(String, Plugin)>,
+    // plugin dirs and trusted keys libraries are checked against before loading, shared with reloads
+    library: Arc<LibraryPolicy>,
 }
 
 impl PluginMgr {
-    pub fn new(path: &str) -> Result<PluginMgr, Box<dyn Error>> {
+    pub fn new(path: &str, library: LibraryPolicy) -> Result<PluginMgr, Box<dyn Error>> {
         if !Path::new(path).exists() {
             let mut dir_path_vec:Vec<&str> = path.split('/').collect();
             dir_path_vec.pop();
@@ -150,93 +855,607 @@ impl PluginMgr {
             fs::File::create(path)?;
             generate_cfg(path)?;
         }
-        let mut obj = PluginMgr {config_path: String::from(path), plugin_cfg: HashMap::new(), plugins: HashMap::new()};
+        let mut obj = PluginMgr {config_path: String::from(path), plugin_cfg: HashMap::new(), plugins: HashMap::new(), stuck: vec![], library: Arc::new(library)};
+        let mut wanted_plugins: Vec<String> = vec![];
         let config_str = fs::read_to_string(path)?;
         let config_docs = YamlLoader::load_from_str(config_str.as_str())?;
         let config = &config_docs[0];
         let plugin_cfg = &config["plugins"];
-        for (name, info) in plugin_cfg.as_hash().ok_or("read plugin config failed".to_owned())?.into_iter() {
-            let name = name.as_str().ok_or("read plugin name failed")?;
-            let path = info["path"].as_str().ok_or("read plugin path failed")?;
-            let active = info["active"].as_bool().ok_or("read plugin active failed")?;
-            obj.add_plugin_inner(name, path, active).expect("initial plugin manage failed: ");
-            info!("plugin added, name:{:?}, path: {}, active: {}", name, path, active);
+        for (name, plugin_info) in parse_plugins(plugin_cfg)? {
+            let desired = plugin_info.desired;
+            info!("plugin added, name:{:?}, path: {}, desired: {}", name, plugin_info.path, desired.as_str());
+            obj.plugin_cfg.insert(name.clone(), plugin_info);
+            if desired == DesiredState::Running {
+                wanted_plugins.push(name);
+            }
+        }
+
+        // dependencies are started first
+        for name in obj.start_order()? {
+            if wanted_plugins.contains(&name) {
+                // a refused or broken library is reported in its state, the other plugins still start
+                match obj.start_plugin_inner(&name) {
+                    Ok(_) => {},
+                    Err(PluginError::Conflict(e)) => {
+                        info!("plugin[{}] waits for its dependencies: {}", name, e);
+                        obj.wait_for_dependencies(&name);
+                    },
+                    Err(e) => error!("start plugin[{}] failed: {}", name, e),
+                }
+            }
         }
         Ok(obj)
     }
 
-    fn start_plugin_inner(&mut self, name: &str) -> Result<String, String> {
+    // `check_plugin` starts the plugin once the plugins it depends on are running
+    fn wait_for_dependencies(&mut self, name: &str) {
+        if let Some(plugin_info) = self.plugin_cfg.get_mut(name) {
+            plugin_info.restarts.next_restart = Some(Instant::now());
+        }
+    }
+
+    // Plugin names ordered so every plugin comes after its dependencies
+    fn start_order(&self) -> Result<Vec<String>, String> {
+        dependency_order(&self.plugin_cfg)
+    }
+
+    // Running plugins that depend on `name`, directly or not, in the order they have to be stopped
+    fn running_dependents(&self, name: &str) -> Result<Vec<String>, String> {
+        let mut dependents: Vec<String> = vec![String::from(name)];
+        let order = self.start_order()?;
+        for plugin_name in order.iter() {
+            let plugin_info = &self.plugin_cfg[plugin_name];
+            if plugin_info.depends_on.iter().any(|d| dependents.contains(d)) {
+                dependents.push(plugin_name.clone());
+            }
+        }
+        Ok(order.into_iter().rev()
+                .filter(|n| n != name && dependents.contains(n) && self.plugins.contains_key(n))
+                .collect())
+    }
+
+    // all plugins sorted by name, without config and restart history
+    pub fn plugin_views(&self) -> Vec<PluginView> {
+        let mut names: Vec<&String> = self.plugin_cfg.keys().collect();
+        names.sort();
+        names.into_iter().filter_map(|name| self.plugin_view(name, false)).collect()
+    }
+
+    pub fn plugin_view(&self, name: &str, detail: bool) -> Option<PluginView> {
+        let plugin_info = self.plugin_cfg.get(name)?;
+        let running = self.plugins.get(name);
+        let loaded = running.is_some() || self.stuck.iter().any(|(n, _)| n == name);
+        let file = match running {
+            Some(plugin) => Some(String::from(plugin.path())),
+            None => self.library.resolve(&plugin_info.path).ok(),
+        };
+        let library = file.as_ref().and_then(|f| fs::metadata(f).ok()).map(|meta| LibraryInfo {
+            size: meta.len(),
+            modified: meta.modified().ok()
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+            loaded,
+        });
+        let last_error = match &plugin_info.state {
+            PluginState::Failed {reason, ..} | PluginState::Degraded {reason} => Some(reason.clone()),
+            PluginState::Stuck => Some(format!("did not stop within {}s", plugin_info.stop_timeout)),
+            _ => plugin_info.config_error.clone()
+                    .or_else(|| plugin_info.restarts.history.last().map(|r| r.reason.clone())),
+        };
+        Some(PluginView {
+            name: String::from(name),
+            path: plugin_info.path.clone(),
+            file,
+            isolation: plugin_info.isolation,
+            desired: plugin_info.desired,
+            state: plugin_info.state.clone(),
+            uptime: running.map(|p| p.uptime().as_secs()).unwrap_or(0),
+            restart_count: plugin_info.restarts.count,
+            crash_loop: plugin_info.restarts.crash_loop,
+            last_error,
+            depends_on: plugin_info.depends_on.clone(),
+            library,
+            config: if detail { Some(plugin_info.config.clone()) } else { None },
+            restart_history: if detail { Some(plugin_info.restarts.history.clone()) } else { None },
+        })
+    }
+
+    // Take every running plugin out of the manager for shutdown, dependents first. The desired
+    // state is kept, the plugins start again with rsu.
+    pub fn detach_all(&mut self) -> Vec<(String, Plugin, Duration)> {
+        let order = match self.start_order() {
+            Ok(order) => order,
+            Err(e) => {
+                error!("get plugin stop order failed: {}", e);
+                self.plugins.keys().cloned().collect()
+            }
+        };
+        let mut detached: Vec<(String, Plugin, Duration)> = vec![];
+        for name in order.iter().rev() {
+            let plugin_info = match self.plugin_cfg.get_mut(name) {
+                Some(plugin_info) if !plugin_info.stopping => plugin_info,
+                _ => continue,
+            };
+            if let Some(plugin) = self.plugins.remove(name) {
+                plugin_info.stopping = true;
+                plugin_info.set_state(name, PluginState::Stopping);
+                detached.push((name.clone(), plugin, plugin_info.stop_timeout()));
+            }
+        }
+        detached
+    }
+
+    fn start_plugin_inner(&mut self, name: &str) -> Result<String, PluginError> {
         if self.plugins.contains_key(name) {
             debug!("plugin[{}] is already running", name);
             return Ok(format!("plugin[{}] is already running", name));
         }
-        let mut plugin_info = self.plugin_cfg.get_mut(name).ok_or(format!("get plugin[{}] info failed, plugin does not exist", name))?;
-        plugin_info.active = true;
-        let mut plugin = Plugin::new(&plugin_info.path[..])?;
-
-        plugin.start()?;
+        let cfg_dir = self.config_dir();
+        let mut plugin_info = self.plugin_cfg.get_mut(name)
+                                    .ok_or_else(|| PluginError::NotFound(format!("get plugin[{}] info failed, plugin does not exist", name)))?;
+        if plugin_info.stopping {
+            return Err(PluginError::Conflict(format!("plugin[{}] is stopping, try again later", name)));
+        }
+        if plugin_info.stuck {
+            return Err(PluginError::Conflict(format!("plugin[{}] is stuck, it did not stop within {}s", name, plugin_info.stop_timeout)));
+        }
+        for dep in plugin_info.depends_on.iter() {
+            if !self.plugins.contains_key(dep) {
+                return Err(PluginError::Conflict(format!("plugin[{}] depends on plugin[{}], which is not running", name, dep)));
+            }
+        }
+        plugin_info.desired = DesiredState::Running;
+        plugin_info.reset_restarts();
+        plugin_info.set_state(name, PluginState::Starting);
+        let path = plugin_info.path.clone();
+        let library = &self.library;
+        let started = plugin_info.load_config(name, &cfg_dir).and_then(|config| {
+            let mut plugin = Plugin::new(name, &path[..], plugin_info.isolation, library)?;
+            plugin.start(&config)?;
+            Ok(plugin)
+        });
+        let plugin = match started {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                // the plugin is wanted running, `check_plugin` retries it according to its policy
+                if plugin_info.schedule_restart(true, &e) {
+                    info!("plugin[{}] will be started again, retry {}/{}", name, plugin_info.restarts.count, plugin_info.max_retries);
+                    plugin_info.set_state(name, PluginState::Restarting);
+                } else {
+                    plugin_info.set_state(name, PluginState::failed(&e));
+                }
+                return Err(PluginError::Failed(e))
+            }
+        };
+        plugin_info.set_state(name, PluginState::Running);
         debug!("plugin[{}] started up successfully", name);
         
+        let file = String::from(plugin.path());
         self.plugins.insert(String::from(name), plugin);
+        self.snapshot_library(name, &file);
         Ok(format!("plugin[{}] is running", name))
     }
 
-    pub fn start_plugin(&mut self, name: &str) -> Result<String, String> {
-        let desc = self.start_plugin_inner(name)?;
+    pub fn start_plugin(&mut self, name: &str) -> Result<String, PluginError> {
+        let ret = self.start_plugin_inner(name);
+        // a failed start is persisted as well
         self.flush_cfg_to_file()?;
+        let desc = ret?;
         info!("plugin[{}] started up successfully", name);
         Ok(desc)
     }
 
-    pub fn stop_plugin(&mut self, name: &str) -> Result<String, String> {
-        if !self.plugins.contains_key(name) {
-            debug!("plugin[{}] is not running", name);
-            return Ok(format!("plugin[{}] is not running", name))
+    // First half of a stop: takes the plugin and its running dependents out of the manager,
+    // dependents first, so the caller can stop them without holding the manager lock and
+    // hand each result to `finish_stop`.
+    pub fn detach_plugin(&mut self, name: &str) -> Result<Vec<(String, Plugin, Duration)>, PluginError> {
+        if !self.plugin_cfg.contains_key(name) {
+            return Err(PluginError::NotFound(format!("stop plugin[{}], plugin does not exist", name)))
         }
-        self.plugins.get_mut(name).ok_or(format!("get plugin[{}] failed from plugins", name))?.stop()?;
-        self.plugins.remove(name);
-        let mut plugin_info = self.plugin_cfg.get_mut(name)
-                                    .ok_or(format!("stop plugin[{}], get plugin info failed from plugin cfg", name))?;
-        plugin_info.active = false;
+        let mut order = self.running_dependents(name)?;
+        if !order.is_empty() {
+            info!("stop plugin[{}] also stops its dependents: {:?}", name, order);
+        }
+        order.push(String::from(name));
+
+        let mut detached: Vec<(String, Plugin, Duration)> = vec![];
+        for plugin_name in order {
+            match self.detach_one(&plugin_name) {
+                Ok(Some((plugin, timeout))) => detached.push((plugin_name, plugin, timeout)),
+                Ok(None) => {},
+                Err(e) => {
+                    for (rest_name, rest_plugin, _) in detached {
+                        self.reattach_plugin(&rest_name, rest_plugin);
+                    }
+                    return Err(e)
+                }
+            }
+        }
+        Ok(detached)
+    }
+
+    // put a detached plugin that was not stopped back
+    pub fn reattach_plugin(&mut self, name: &str, plugin: Plugin) {
+        if let Some(plugin_info) = self.plugin_cfg.get_mut(name) {
+            plugin_info.stopping = false;
+            plugin_info.desired = DesiredState::Running;
+            plugin_info.set_state(name, PluginState::Running);
+        }
+        self.plugins.insert(String::from(name), plugin);
+    }
+
+    fn detach_one(&mut self, name: &str) -> Result<Option<(Plugin, Duration)>, PluginError> {
+        let plugin_info = self.plugin_cfg.get_mut(name)
+                                    .ok_or_else(|| PluginError::NotFound(format!("stop plugin[{}], plugin does not exist", name)))?;
+        if plugin_info.stopping {
+            return Err(PluginError::Conflict(format!("plugin[{}] is already stopping", name)));
+        }
+        let plugin = match self.plugins.remove(name) {
+            Some(p) => p,
+            None => {
+                // the plugin may be waiting for a restart, or have failed
+                if plugin_info.desired == DesiredState::Running {
+                    plugin_info.desired = DesiredState::Stopped;
+                    if plugin_info.restarts.next_restart.take().is_some() {
+                        info!("plugin[{}] pending restart cancelled", name);
+                    }
+                    if !plugin_info.stuck {
+                        plugin_info.set_state(name, PluginState::Stopped);
+                    }
+                    self.flush_cfg_to_file()?;
+                }
+                debug!("plugin[{}] is not running", name);
+                return Ok(None)
+            }
+        };
+        plugin_info.desired = DesiredState::Stopped;
+        plugin_info.stopping = true;
+        plugin_info.set_state(name, PluginState::Stopping);
+        debug!("begin to stop plugin {}", name);
+        Ok(Some((plugin, plugin_info.stop_timeout())))
+    }
+
+    pub fn finish_stop(&mut self, name: &str, plugin: Plugin, ret: Result<i32, StopError>) -> Result<String, PluginError> {
+        if let Some(plugin_info) = self.plugin_cfg.get_mut(name) {
+            plugin_info.stopping = false;
+            match &ret {
+                Err(StopError::Stuck(_)) => {
+                    plugin_info.stuck = true;
+                    plugin_info.set_state(name, PluginState::Stuck);
+                },
+                _ => {
+                    plugin_info.set_state(name, PluginState::Stopped);
+                },
+            }
+        }
+        let desc = match ret {
+            Ok(_) => {
+                info!("plugin[{}] stopped", name);
+                Ok(format!("plugin[{}] stopped", name))
+            },
+            Err(StopError::Failed(e)) => {
+                info!("plugin[{}] stopped with error: {}", name, e);
+                Ok(format!("plugin[{}] stopped with error: {}", name, e))
+            },
+            Err(StopError::Stuck(timeout)) => {
+                error!("plugin[{}] did not stop within {:?}, detach it", name, timeout);
+                self.stuck.push((String::from(name), plugin));
+                Err(PluginError::Failed(format!("plugin[{}] is stuck, it did not stop within {:?} and has been detached", name, timeout)))
+            },
+        };
         self.flush_cfg_to_file()?;
-        info!("plugin[{}] stopped", name);
-        Ok(format!("plugin[{}] stopped", name))
+        desc
+    }
+
+    // Stopped plugins that were stuck are finally released
+    fn reap_stuck(&mut self) {
+        let mut i = 0;
+        while i < self.stuck.len() {
+            if !self.stuck[i].1.exited() {
+                i += 1;
+                continue;
+            }
+            let (name, mut plugin) = self.stuck.remove(i);
+            if let Err(e) = plugin.stop(Duration::from_secs(0)) {
+                debug!("stuck plugin[{}] stopped with error: {}", name, e);
+            }
+            if let Some(plugin_info) = self.plugin_cfg.get_mut(&name) {
+                plugin_info.stuck = false;
+                // a plugin that got stuck after it failed, or while it was reloaded, is still wanted running
+                if plugin_info.desired == DesiredState::Running && plugin_info.schedule_restart(true, "stuck while stopping") {
+                    plugin_info.set_state(&name, PluginState::Restarting);
+                } else {
+                    plugin_info.set_state(&name, PluginState::Stopped);
+                }
+            }
+            info!("stuck plugin[{}] finally stopped", name);
+        }
     }
 
-    fn add_plugin_inner(&mut self, name: &str, path: &str, active: bool) -> Result<String, String> {
+    fn add_plugin_inner(&mut self, name: &str, path: &str, desired: DesiredState, depends_on: &[String]) -> Result<String, PluginError> {
+        check_name(name).map_err(PluginError::Invalid)?;
         if self.plugin_cfg.contains_key(name) {
             return Ok(format!("plugin[{}] has been added", name));
         }
-        self.plugin_cfg.insert(String::from(name), PluginInfo {path: String::from(path), active});
-        if active {
+        for dep in depends_on {
+            if !self.plugin_cfg.contains_key(dep) {
+                return Err(PluginError::Invalid(format!("plugin[{}] depends on plugin[{}], which does not exist", name, dep)))
+            }
+        }
+        self.library.resolve(path).map_err(|e| PluginError::Invalid(format!("add plugin[{}] failed: {}", name, e)))?;
+        let mut plugin_info = PluginInfo::new(path, desired);
+        plugin_info.depends_on = depends_on.to_vec();
+        self.plugin_cfg.insert(String::from(name), plugin_info);
+        events::publish(EventKind::Added {plugin: String::from(name), path: String::from(path)});
+        if desired == DesiredState::Running {
             self.start_plugin_inner(name)?;
         }
         Ok(format!("plugin[{}] added", name))
     }
 
-    pub fn add_plugin(&mut self, name: &str, path: &str, active: bool) -> Result<String, String> {
-        let desc = self.add_plugin_inner(name, path, active)?;
+    pub fn add_plugin(&mut self, name: &str, path: &str, desired: DesiredState, depends_on: &[String]) -> Result<String, PluginError> {
+        let desc = self.add_plugin_inner(name, path, desired, depends_on)?;
         self.flush_cfg_to_file()?;
         info!("add plugin[{}] successful", name);
         Ok(desc)
     }
 
-    pub fn remove_plugin(&mut self, name: &str) -> Result<String, String> {
-        if self.plugins.contains_key(name) {
-            self.stop_plugin(name)?;
+    // A plugin can only be removed when no other plugin depends on it
+    pub fn check_removable(&self, name: &str) -> Result<(), PluginError> {
+        if !self.plugin_cfg.contains_key(name) {
+            return Err(PluginError::NotFound(format!("remove plugin[{}] failed, plugin does not exist", name)))
         }
-        self.plugin_cfg.remove(name);
+        let dependents: Vec<&String> = self.plugin_cfg.iter()
+                                            .filter(|(_, info)| info.depends_on.iter().any(|d| d == name))
+                                            .map(|(n, _)| n)
+                                            .collect();
+        if !dependents.is_empty() {
+            return Err(PluginError::Conflict(format!("plugin[{}] can not be removed, plugins {:?} depend on it", name, dependents)))
+        }
+        Ok(())
+    }
+
+    // The plugin has to be stopped first, see `detach_plugin`
+    pub fn remove_plugin(&mut self, name: &str) -> Result<String, PluginError> {
+        self.check_removable(name)?;
+        self.forget_plugin(name)?;
         self.flush_cfg_to_file()?;
         info!("remove plugin[{}] successful", name);
         Ok(format!("plugin[{}] has been removed", name))
     }
 
+    fn forget_plugin(&mut self, name: &str) -> Result<(), PluginError> {
+        if self.plugins.contains_key(name) || self.plugin_cfg.get(name).map(|p| p.stopping).unwrap_or(false) {
+            return Err(PluginError::Conflict(format!("plugin[{}] is running, it has to be stopped before it is removed", name)))
+        }
+        self.plugin_cfg.remove(name);
+        events::publish(EventKind::Removed {plugin: String::from(name)});
+        Ok(())
+    }
+
+    // Replace the library of a plugin with the build at `path` (or a new build at the same path).
+    // The old build is restored if the new one fails to load, or reports an error within `grace`.
+    // The plugin is taken out of the manager until the returned job is handed to `finish_reload`.
+    pub fn begin_reload(&mut self, name: &str, path: Option<&str>, grace: Duration) -> Result<ReloadJob, PluginError> {
+        let cfg_dir = self.config_dir();
+        let backup = self.backup_path(name);
+        let plugin_info = self.plugin_cfg.get_mut(name)
+                            .ok_or_else(|| PluginError::NotFound(format!("reload plugin[{}] failed, plugin does not exist", name)))?;
+        if plugin_info.stopping || plugin_info.stuck {
+            return Err(PluginError::Conflict(format!("plugin[{}] is {}, try again later", name, plugin_info.state)))
+        }
+        // an invalid config refuses the reload before the old plugin is stopped
+        let config = plugin_info.load_config(name, &cfg_dir)
+                        .map_err(|e| format!("reload plugin[{}] failed: {}", name, e))?;
+        let old_path = plugin_info.path.clone();
+        let new_path = String::from(path.unwrap_or(&old_path[..]));
+        self.library.resolve(&new_path).map_err(|e| PluginError::Invalid(format!("reload plugin[{}] failed: {}", name, e)))?;
+
+        let plugin = self.plugins.remove(name);
+        let plugin_info = self.plugin_cfg.get_mut(name)
+                            .ok_or_else(|| PluginError::NotFound(format!("reload plugin[{}] failed, plugin does not exist", name)))?;
+        plugin_info.stopping = true;
+        plugin_info.restarts.next_restart = None;
+        if plugin.is_some() {
+            plugin_info.set_state(name, PluginState::Stopping);
+        }
+        Ok(ReloadJob {
+            name: String::from(name),
+            old_path,
+            new_path,
+            isolation: plugin_info.isolation,
+            config,
+            grace,
+            stop_timeout: plugin_info.stop_timeout(),
+            was_running: plugin.is_some(),
+            plugin,
+            backup,
+            library: Arc::clone(&self.library),
+        })
+    }
+
+    pub fn finish_reload(&mut self, job: ReloadJob, outcome: ReloadOutcome) -> Result<String, PluginError> {
+        let name = &job.name[..];
+        let plugin_info = self.plugin_cfg.get_mut(name)
+                            .ok_or_else(|| format!("reload plugin[{}], get plugin info failed from plugin cfg", name))?;
+        plugin_info.stopping = false;
+        let ret = match outcome {
+            ReloadOutcome::Reloaded(plugin) => {
+                let file = String::from(plugin.path());
+                if plugin_info.path != job.new_path {
+                    events::publish(EventKind::Library {plugin: String::from(name), path: job.new_path.clone()});
+                }
+                plugin_info.path = job.new_path.clone();
+                plugin_info.desired = DesiredState::Running;
+                plugin_info.set_state(name, PluginState::Running);
+                plugin_info.reset_restarts();
+                self.plugins.insert(String::from(name), plugin);
+                self.snapshot_library(name, &file);
+                info!("plugin[{}] reloaded from {}", name, file);
+                Ok(format!("plugin[{}] reloaded from {}", name, file))
+            },
+            ReloadOutcome::Stuck(plugin, timeout) => {
+                error!("plugin[{}] did not stop within {:?} for the reload, detach it", name, timeout);
+                plugin_info.stuck = true;
+                plugin_info.set_state(name, PluginState::Stuck);
+                self.stuck.push((String::from(name), plugin));
+                Err(PluginError::Conflict(format!("reload plugin[{}] failed, old plugin did not stop within {:?}", name, timeout)))
+            },
+            ReloadOutcome::RolledBack(plugin, e) => {
+                if let Some(plugin) = plugin {
+                    plugin_info.set_state(name, PluginState::Running);
+                    self.plugins.insert(String::from(name), plugin);
+                }
+                Err(PluginError::Failed(format!("reload plugin[{}] failed, rolled back to {}: {}", name, job.old_path, e)))
+            },
+            ReloadOutcome::RollbackFailed(e, err) => {
+                plugin_info.set_state(name, PluginState::failed(&err));
+                Err(PluginError::Failed(format!("reload plugin[{}] failed: {}, rollback failed: {}", name, e, err)))
+            },
+        };
+        self.flush_cfg_to_file()?;
+        ret
+    }
+
+    pub fn is_running(&self, name: &str) -> bool {
+        self.plugins.contains_key(name)
+    }
+
+    // Point a stopped plugin at a new library, e.g. an uploaded version, it is used at the next start.
+    // A running plugin is reloaded from the new library instead, see `begin_reload`.
+    pub fn upgrade_plugin(&mut self, name: &str, path: &str) -> Result<String, PluginError> {
+        if self.plugins.contains_key(name) {
+            return Err(PluginError::Conflict(format!("plugin[{}] is running, reload it from {}", name, path)))
+        }
+        let plugin_info = self.plugin_cfg.get_mut(name)
+                            .ok_or_else(|| PluginError::NotFound(format!("upgrade plugin[{}] failed, plugin does not exist", name)))?;
+        if plugin_info.stopping || plugin_info.stuck {
+            return Err(PluginError::Conflict(format!("plugin[{}] is {}, try again later", name, plugin_info.state)))
+        }
+        self.library.resolve(path).map_err(|e| PluginError::Invalid(format!("upgrade plugin[{}] failed: {}", name, e)))?;
+        plugin_info.path = String::from(path);
+        events::publish(EventKind::Library {plugin: String::from(name), path: String::from(path)});
+        self.flush_cfg_to_file()?;
+        info!("plugin[{}] will start from {}", name, path);
+        Ok(format!("plugin[{}] will start from {}", name, path))
+    }
+
+    // Plan how to bring the plugins in line with a desired `plugins` mapping in the shape of plugins.yaml:
+    // plugins missing from it are removed, new ones added, and the others stopped, started, pointed at
+    // a new library or given new settings. The whole mapping is checked before anything changes.
+    pub fn plan_reconcile(&self, plugin_cfg: &Yaml) -> Result<Reconcile, PluginError> {
+        let cfg_dir = self.config_dir();
+        let mut wanted: HashMap<String, PluginInfo> = HashMap::new();
+        for (name, plugin_info) in parse_plugins(plugin_cfg).map_err(PluginError::Invalid)? {
+            self.library.resolve(&plugin_info.path).map_err(|e| PluginError::Invalid(format!("plugin[{}]: {}", name, e)))?;
+            resolve_config(&name, &cfg_dir, plugin_info.config_path.as_deref(), plugin_info.config_inline.as_ref())
+                .map_err(PluginError::Invalid)?;
+            wanted.insert(name, plugin_info);
+        }
+        let order = dependency_order(&wanted).map_err(PluginError::Invalid)?;
+        let changes = plan(&self.plugin_cfg, |name| self.plugins.contains_key(name), &wanted, &order);
+        Ok(Reconcile {wanted, order, changes})
+    }
+
+    // Last step of a reconcile, once the other changes are applied: the wanted plugins are started,
+    // dependencies first, this also starts the wanted dependents a stop took down with it
+    pub fn finish_reconcile(&mut self, reconcile: &Reconcile) -> Result<Vec<ChangeReport>, PluginError> {
+        let mut reports: Vec<ChangeReport> = vec![];
+        for name in reconcile.order.iter() {
+            if reconcile.wanted[name].desired != DesiredState::Running || self.plugins.contains_key(name) {
+                continue;
+            }
+            let change = Change::Start {plugin: name.clone()};
+            let ret = self.apply_change(&change, reconcile);
+            reports.push(ChangeReport::applied(change, ret));
+        }
+        // a plugin that failed to start is still wanted running
+        for (name, plugin_info) in reconcile.wanted.iter() {
+            if let Some(current) = self.plugin_cfg.get_mut(name) {
+                current.desired = plugin_info.desired;
+            }
+        }
+        self.flush_cfg_to_file()?;
+        Ok(reports)
+    }
+
+    // The part of a change made under the manager lock. The caller stops the plugin of a remove
+    // or stop change before, and reloads a running plugin that is pointed at a new library.
+    pub fn apply_change(&mut self, change: &Change, reconcile: &Reconcile) -> Result<(), PluginError> {
+        let wanted = &reconcile.wanted;
+        match change {
+            Change::Remove {plugin} => {
+                self.forget_plugin(plugin)?;
+            },
+            Change::Stop {..} => {},
+            Change::Add {plugin, path} => {
+                let mut plugin_info = PluginInfo::new(path, DesiredState::Stopped);
+                plugin_info.apply_settings(&wanted[plugin]);
+                self.plugin_cfg.insert(plugin.clone(), plugin_info);
+                events::publish(EventKind::Added {plugin: plugin.clone(), path: path.clone()});
+            },
+            Change::Path {plugin, to, ..} => {
+                self.upgrade_plugin(plugin, to)?;
+            },
+            Change::Update {plugin, ..} => {
+                let plugin_info = self.plugin_cfg.get_mut(plugin)
+                                    .ok_or_else(|| PluginError::NotFound(format!("update plugin[{}] failed, plugin does not exist", plugin)))?;
+                plugin_info.apply_settings(&wanted[plugin]);
+            },
+            Change::Start {plugin} => {
+                self.start_plugin_inner(plugin)?;
+            },
+        }
+        Ok(())
+    }
+
+    // directory of plugins.yaml
+    fn config_dir(&self) -> String {
+        let mut dir_path_vec: Vec<&str> = self.config_path.split('/').collect();
+        dir_path_vec.pop();
+        dir_path_vec.join("/")
+    }
+
+    fn backup_path(&self, name: &str) -> String {
+        format!("{}/rollback/lib{}.so", self.config_dir(), name)
+    }
+
+    // keep a copy of the library that is running and its signature, used to roll back a failed reload
+    fn snapshot_library(&self, name: &str, path: &str) {
+        let backup = self.backup_path(name);
+        let mut dir_path_vec: Vec<&str> = backup.split('/').collect();
+        dir_path_vec.pop();
+        let ret = fs::create_dir_all(dir_path_vec.join("/"))
+                    .and_then(|_| fs::copy(path, &backup))
+                    .and_then(|_| copy_signature(path, &backup));
+        match ret {
+            Ok(_) => debug!("plugin[{}] library {} saved to {}", name, path, backup),
+            Err(e) => debug!("plugin[{}] save library {} for rollback failed: {:?}", name, path, e),
+        }
+    }
+
     fn flush_cfg_to_file(&mut self) -> Result<(), String> {
         let mut node_map: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
         for (name, info) in self.plugin_cfg.iter_mut() {
             let mut info_map: LinkedHashMap<Yaml, Yaml> = LinkedHashMap::new();
             info_map.insert(Yaml::from_str("path"), Yaml::from_str(&info.path[..]));
-            info_map.insert(Yaml::from_str("active"), Yaml::from_str(if info.active {"true"} else {"false"}));
+            info_map.insert(Yaml::from_str("desired"), Yaml::from_str(info.desired.as_str()));
+            info_map.insert(Yaml::from_str("state"), info.state.to_yaml());
+            info_map.insert(Yaml::from_str("isolation"), Yaml::from_str(info.isolation.as_str()));
+            info_map.insert(Yaml::from_str("restart"), Yaml::from_str(info.restart.as_str()));
+            info_map.insert(Yaml::from_str("max_retries"), Yaml::Integer(info.max_retries as i64));
+            info_map.insert(Yaml::from_str("backoff"), Yaml::Integer(info.backoff as i64));
+            info_map.insert(Yaml::from_str("stop_timeout"), Yaml::Integer(info.stop_timeout as i64));
+            if !info.depends_on.is_empty() {
+                let deps = info.depends_on.iter().map(|d| Yaml::from_str(d)).collect();
+                info_map.insert(Yaml::from_str("depends_on"), Yaml::Array(deps));
+            }
+            if let Some(inline) = &info.config_inline {
+                info_map.insert(Yaml::from_str("config"), inline.clone());
+            } else if let Some(config_path) = &info.config_path {
+                info_map.insert(Yaml::from_str("config"), Yaml::String(config_path.clone()));
+            }
             let info_node: Yaml = Yaml::Hash(info_map);
             node_map.insert(Yaml::from_str(name), info_node);
         }
@@ -248,7 +1467,8 @@ impl PluginMgr {
         let mut emitter = YamlEmitter::new(&mut out_str);
         match emitter.dump(&root_node) {
             Ok(_) => {
-                let _ = fs::write(&self.config_path[..], out_str);
+                fs::write(&self.config_path[..], out_str)
+                    .map_err(|e| format!("write plugin config {} failed: {:?}", self.config_path, e))?;
                 debug!("flush config into plugin config successful");
                 Ok(())
             },
@@ -258,39 +1478,424 @@ impl PluginMgr {
         }
     }
 
+    // First half of a check: takes the plugins that should be running but reported an error
+    // or exited out of the manager, so they are stopped without holding the manager lock
+    pub fn detach_failed(&mut self) -> Vec<FailedPlugin> {
+        let mut detached: Vec<FailedPlugin> = vec![];
+        for (name, plugin_info) in self.plugin_cfg.iter_mut() {
+            if plugin_info.desired != DesiredState::Running || plugin_info.stopping {
+                continue;
+            }
+            let (failed, reason) = match self.plugins.get_mut(name) {
+                Some(plugin) => match plugin.check() {
+                    Err(e) => (true, e),
+                    Ok(_) if plugin.exited() => (false, String::from("plugin exited")),
+                    Ok(_) => continue,
+                },
+                None => continue,
+            };
+            error!("plugin[{}] run failed, stop plugin: {}", name, reason);
+            if failed {
+                events::publish(EventKind::CheckFailed {plugin: name.clone(), reason: reason.clone()});
+            }
+            if let Some(plugin) = self.plugins.remove(name) {
+                plugin_info.stopping = true;
+                detached.push(FailedPlugin {name: name.clone(), plugin, timeout: plugin_info.stop_timeout(), failed, reason});
+            }
+        }
+        detached
+    }
+
+    // Restart a stopped failed plugin according to its policy, a stuck one is kept until it exits
+    pub fn finish_failed(&mut self, detached: FailedPlugin, ret: Result<i32, StopError>) -> Result<(), String> {
+        let FailedPlugin {name, plugin, failed, reason, ..} = detached;
+        let plugin_info = self.plugin_cfg.get_mut(&name)
+                            .ok_or_else(|| format!("check plugin[{}], get plugin info failed from plugin cfg", name))?;
+        plugin_info.stopping = false;
+        match ret {
+            Ok(_) => {},
+            Err(StopError::Failed(e)) => debug!("plugin[{}] stopped with error: {}", name, e),
+            Err(StopError::Stuck(timeout)) => {
+                error!("plugin[{}] did not stop within {:?} after it failed ({}), detach it", name, timeout, reason);
+                plugin_info.stuck = true;
+                plugin_info.set_state(&name, PluginState::Stuck);
+                self.stuck.push((name, plugin));
+                return self.flush_cfg_to_file()
+            }
+        }
+        if plugin_info.schedule_restart(failed, &reason) {
+            info!("plugin[{}] will be restarted, retry {}/{}", name, plugin_info.restarts.count, plugin_info.max_retries);
+            plugin_info.set_state(&name, PluginState::Restarting);
+        } else if failed {
+            if plugin_info.restarts.crash_loop {
+                error!("plugin[{}] is crash looping, gave up after {} retries", name, plugin_info.max_retries);
+            }
+            plugin_info.set_state(&name, PluginState::failed(&reason));
+        } else {
+            plugin_info.set_state(&name, PluginState::Stopped);
+        }
+        self.flush_cfg_to_file()
+    }
+
+    // Compare every plugin that should be running with what it does and restart the failed ones
+    // that are due, after `detach_failed` took out the ones to stop. State changes are written back
+    // to plugins.yaml.
     pub fn check_plugin(&mut self) -> Result<(), String> {
+        self.reap_stuck();
+        let cfg_dir = self.config_dir();
+        let mut errors: Vec<String> = vec![];
+        let mut changed = false;
+        let library = &self.library;
         for (name, plugin_info) in self.plugin_cfg.iter_mut() {
-            if plugin_info.active {
-                let plugin = self.plugins.get_mut(name).ok_or(format!("get plugin[{}] failed from plugins", name))?;
-                match plugin.check() {
-                    Ok(_) => {
-                        debug!("plugin[{}] is running", name);
-                    },
-                    Err(_) => {
-                        error!("plugin run failed, stop plugin: {}", name);
-                        plugin.stop()?;
-                        self.plugins.remove(name);
-                        plugin_info.active = false;
-                        return Ok(())
+            if plugin_info.desired != DesiredState::Running || plugin_info.stopping {
+                continue;
+            }
+            let missing_dep = {
+                let plugins = &self.plugins;
+                plugin_info.depends_on.iter().find(|d| !plugins.contains_key(*d)).cloned()
+            };
+            match self.plugins.get_mut(name) {
+                Some(plugin) => {
+                    // a plugin that failed since is stopped by the next `detach_failed`
+                    if plugin.check().is_err() || plugin.exited() {
+                        continue;
+                    }
+                    if plugin_info.restarts.count > 0 && plugin.uptime() >= STABLE_AFTER {
+                        plugin_info.restarts.count = 0;
+                    }
+                    let state = match missing_dep {
+                        Some(dep) => PluginState::Degraded {reason: format!("plugin[{}] is not running", dep)},
+                        None => PluginState::Running,
+                    };
+                    changed |= plugin_info.set_state(name, state);
+                    debug!("plugin[{}] is running", name);
+                },
+                None => {
+                    if !plugin_info.restart_due() {
+                        continue;
+                    }
+                    if let Some(dep) = missing_dep {
+                        debug!("plugin[{}] waits for plugin[{}] before restart", name, dep);
+                        continue;
+                    }
+                    changed = true;
+                    let started = plugin_info.load_config(name, &cfg_dir).and_then(|config| {
+                        let mut plugin = Plugin::new(name, &plugin_info.path[..], plugin_info.isolation, library)?;
+                        plugin.start(&config)?;
+                        Ok(plugin)
+                    });
+                    match started {
+                        Ok(plugin) => {
+                            info!("plugin[{}] restarted", name);
+                            metrics::plugin_restarted(name, true);
+                            plugin_info.restarts.next_restart = None;
+                            plugin_info.set_state(name, PluginState::Running);
+                            self.plugins.insert(name.clone(), plugin);
+                        },
+                        Err(e) => {
+                            error!("restart plugin[{}] failed: {}", name, e);
+                            events::publish(EventKind::CheckFailed {plugin: name.clone(), reason: e.clone()});
+                            metrics::plugin_restarted(name, false);
+                            if !plugin_info.schedule_restart(true, &e) {
+                                plugin_info.set_state(name, PluginState::failed(&e));
+                            }
+                            errors.push(format!("restart plugin[{}] failed: {}", name, e));
+                        }
                     }
                 }
             }
         }
 
-        Ok(())
+        if changed {
+            if let Err(e) = self.flush_cfg_to_file() {
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+
+// Copy the detached signature of the library `from` next to `to`, a stale one is removed
+fn copy_signature(from: &str, to: &str) -> std::io::Result<()> {
+    let (from_sig, to_sig) = (format!("{}.sig", from), format!("{}.sig", to));
+    if Path::new(&from_sig).exists() {
+        return fs::copy(&from_sig, &to_sig).map(|_| ())
+    }
+    match fs::remove_file(&to_sig) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Put the saved build `backup` and its signature back at `file`. The copy is renamed into place,
+// a library that is still mapped keeps its own inode instead of being overwritten under it.
+fn restore_library(backup: &str, file: &str) -> Result<(), String> {
+    let tmp = format!("{}.rollback", file);
+    let ret = fs::copy(backup, &tmp)
+                .and_then(|_| copy_signature(backup, file))
+                .and_then(|_| fs::rename(&tmp, file));
+    ret.map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        format!("restore {} from {} failed: {:?}", file, backup, e)
+    })
+}
+
+// Changes that turn the plugins of `plugin_cfg` into the `wanted` ones, stops and removals come first,
+// starts last in dependency order
+fn plan<R: Fn(&str) -> bool>(plugin_cfg: &HashMap<String, PluginInfo>, running: R,
+                             wanted: &HashMap<String, PluginInfo>, order: &[String]) -> Vec<Change> {
+    let mut removes: Vec<Change> = vec![];
+    let mut stops: Vec<Change> = vec![];
+    let mut adds: Vec<Change> = vec![];
+    let mut paths: Vec<Change> = vec![];
+    let mut updates: Vec<Change> = vec![];
+    let mut starts: Vec<Change> = vec![];
+
+    let mut current_names: Vec<&String> = plugin_cfg.keys().collect();
+    current_names.sort();
+    for name in current_names {
+        if !wanted.contains_key(name) {
+            removes.push(Change::Remove {plugin: name.clone()});
+        }
+    }
+    for name in order {
+        let plugin_info = &wanted[name];
+        let running = running(name);
+        let keep_running = plugin_info.desired == DesiredState::Running;
+        let mut restart = false;
+        match plugin_cfg.get(name) {
+            None => adds.push(Change::Add {plugin: name.clone(), path: plugin_info.path.clone()}),
+            Some(current) => {
+                if current.path != plugin_info.path {
+                    paths.push(Change::Path {plugin: name.clone(), from: current.path.clone(), to: plugin_info.path.clone()});
+                }
+                let fields = current.changed_settings(plugin_info);
+                // a running plugin only picks up a new isolation or config when it is started again
+                restart = running && keep_running && fields.iter().any(|f| *f == "isolation" || *f == "config");
+                if !fields.is_empty() {
+                    updates.push(Change::Update {plugin: name.clone(), fields});
+                }
+                let stop = if running {
+                    !keep_running || restart
+                } else {
+                    // cancels a pending restart
+                    !keep_running && current.desired == DesiredState::Running
+                };
+                if stop {
+                    stops.push(Change::Stop {plugin: name.clone()});
+                }
+            }
+        }
+        if keep_running && (!running || restart) {
+            starts.push(Change::Start {plugin: name.clone()});
+        }
+    }
+
+    let mut changes = removes;
+    changes.append(&mut stops);
+    changes.append(&mut adds);
+    changes.append(&mut paths);
+    changes.append(&mut updates);
+    changes.append(&mut starts);
+    changes
+}
+
+// Load and start the library at `path` as plugin `name`, it has to keep running without error for `grace`
+fn start_with_grace(name: &str, path: &str, isolation: Isolation, library: &LibraryPolicy, config: &str,
+                    grace: Duration, stop_timeout: Duration) -> Result<Plugin, String> {
+    let deadline = Instant::now().checked_add(grace).ok_or_else(|| format!("reload grace {:?} is too long", grace))?;
+    let mut plugin = Plugin::new(name, path, isolation, library)?;
+    plugin.start(config)?;
+
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+        let failure = match plugin.check() {
+            Err(e) => Some(e),
+            Ok(_) if plugin.exited() => Some(String::from("plugin exited")),
+            Ok(_) => None,
+        };
+        if let Some(reason) = failure {
+            if let Err(e) = plugin.stop(stop_timeout) {
+                debug!("stop plugin {} failed: {}", path, e);
+            }
+            return Err(format!("plugin {} failed within {:?}: {}", path, grace, reason))
+        }
+    }
+    Ok(plugin)
+}
+
+// Plugins of a `plugins` mapping in the shape of plugins.yaml, in document order
+fn parse_plugins(plugin_cfg: &Yaml) -> Result<Vec<(String, PluginInfo)>, String> {
+    let mut plugins: Vec<(String, PluginInfo)> = vec![];
+    for (name, info) in plugin_cfg.as_hash().ok_or("read plugin config failed")?.iter() {
+        let name = name.as_str().ok_or("read plugin name failed")?;
+        check_name(name)?;
+        let path = info["path"].as_str().ok_or_else(|| format!("read plugin[{}] path failed", name))?;
+        // `active` is the desired state of plugins.yaml written before `desired` existed
+        let desired = match (info["desired"].as_str(), info["active"].as_bool()) {
+            (Some(desired), _) => DesiredState::parse(desired)?,
+            (None, Some(true)) => DesiredState::Running,
+            (None, Some(false)) => DesiredState::Stopped,
+            (None, None) => return Err(format!("read plugin[{}] desired failed", name)),
+        };
+        let mut plugin_info = PluginInfo::new(path, desired);
+        if let Some(isolation) = info["isolation"].as_str() {
+            plugin_info.isolation = Isolation::parse(isolation)?;
+        }
+        if let Some(policy) = info["restart"].as_str() {
+            plugin_info.restart = RestartPolicy::parse(policy)?;
+        }
+        if let Some(max_retries) = read_count(info, name, "max_retries", 0)? {
+            plugin_info.max_retries = u32::try_from(max_retries).map_err(|_| format!("plugin[{}] max_retries {} is too large", name, max_retries))?;
+        }
+        if let Some(backoff) = read_count(info, name, "backoff", 0)? {
+            plugin_info.backoff = backoff;
+        }
+        // a stop has to give the plugin some time, 0 would detach every plugin as stuck
+        if let Some(stop_timeout) = read_count(info, name, "stop_timeout", 1)? {
+            plugin_info.stop_timeout = stop_timeout;
+        }
+        if let Some(depends_on) = info["depends_on"].as_vec() {
+            for dep in depends_on {
+                plugin_info.depends_on.push(String::from(dep.as_str().ok_or("read plugin depends_on failed")?));
+            }
+        }
+        match &info["config"] {
+            Yaml::String(config_path) => plugin_info.config_path = Some(config_path.clone()),
+            Yaml::Hash(_) => plugin_info.config_inline = Some(info["config"].clone()),
+            Yaml::BadValue | Yaml::Null => {},
+            _ => return Err(format!("read plugin[{}] config failed, expect a file path or a mapping", name)),
+        }
+        plugins.push((String::from(name), plugin_info));
+    }
+    Ok(plugins)
+}
+
+// The reports of a plugin are queued under its name, the queue of the rsu itself can not be shared
+fn check_name(name: &str) -> Result<(), String> {
+    if name == uplink::HOST_QUEUE {
+        return Err(format!("plugin name {} is reserved for the reports of the rsu", name))
+    }
+    Ok(())
+}
+
+// An integer field of a plugin of at least `min`, None when it is not set
+fn read_count(info: &Yaml, name: &str, key: &str, min: i64) -> Result<Option<u64>, String> {
+    match &info[key] {
+        Yaml::Integer(value) if *value >= min => Ok(Some(*value as u64)),
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        value => Err(format!("read plugin[{}] {} failed, expect an integer of at least {}, got {:?}", name, key, min, value)),
     }
 }
 
+// Plugin names ordered so every plugin comes after its dependencies
+fn dependency_order(plugin_cfg: &HashMap<String, PluginInfo>) -> Result<Vec<String>, String> {
+    let mut names: Vec<&String> = plugin_cfg.keys().collect();
+    names.sort();
+    let mut pending: HashMap<&String, usize> = HashMap::new();
+    for name in names.iter() {
+        let plugin_info = &plugin_cfg[*name];
+        for dep in plugin_info.depends_on.iter() {
+            if !plugin_cfg.contains_key(dep) {
+                return Err(format!("plugin[{}] depends on plugin[{}], which does not exist", name, dep))
+            }
+        }
+        pending.insert(name, plugin_info.depends_on.len());
+    }
+
+    let mut order: Vec<String> = vec![];
+    while !pending.is_empty() {
+        let ready: Vec<&String> = names.iter().cloned().filter(|n| pending.get(n) == Some(&0)).collect();
+        if ready.is_empty() {
+            let mut cycle: Vec<&String> = pending.keys().cloned().collect();
+            cycle.sort();
+            return Err(format!("plugin dependency cycle between: {:?}", cycle))
+        }
+        for name in ready {
+            pending.remove(&name);
+            for (other, count) in pending.iter_mut() {
+                *count -= plugin_cfg[*other].depends_on.iter().filter(|d| *d == name).count();
+            }
+            order.push(name.clone());
+        }
+    }
+    Ok(order)
+}
+
+// Read the config document of a plugin, returns the text handed to the plugin and the parsed document
+fn resolve_config(name: &str, cfg_dir: &str, path: Option<&str>, inline: Option<&Yaml>) -> Result<(String, Yaml), String> {
+    let doc = match (inline, path) {
+        (Some(doc), _) => doc.clone(),
+        (None, Some(path)) => {
+            let file = if path.starts_with('/') { String::from(path) } else { format!("{}/{}", cfg_dir, path) };
+            read_config_file(name, &file)?
+        },
+        (None, None) => {
+            let file = format!("{}/plugins/{}.yaml", cfg_dir, name);
+            if !Path::new(&file).exists() {
+                debug!("plugin[{}] has no config, use the plugin defaults", name);
+                return Ok((String::new(), Yaml::Null))
+            }
+            read_config_file(name, &file)?
+        },
+    };
+    match doc {
+        Yaml::Hash(_) => {},
+        Yaml::Null => return Ok((String::new(), Yaml::Null)),
+        _ => return Err(format!("plugin[{}] config must be a yaml mapping", name)),
+    }
+    // ${RSU_ID} and ${HOST_IP} in urls and zenoh paths
+    let doc = identity::expand_yaml(&doc).map_err(|e| format!("plugin[{}] config: {}", name, e))?;
+    let mut text = String::new();
+    YamlEmitter::new(&mut text).dump(&doc).map_err(|e| format!("plugin[{}] dump config failed: {:?}", name, e))?;
+    Ok((text, doc))
+}
+
+fn read_config_file(name: &str, file: &str) -> Result<Yaml, String> {
+    let text = fs::read_to_string(file).map_err(|e| format!("plugin[{}] read config {} failed: {:?}", name, file, e))?;
+    let docs = YamlLoader::load_from_str(&text).map_err(|e| format!("plugin[{}] config is not valid yaml: {}", name, e))?;
+    Ok(docs.into_iter().next().unwrap_or(Yaml::Null))
+}
+
+pub(crate) fn yaml_to_json(doc: &Yaml) -> serde_json::Value {
+    match doc {
+        Yaml::Real(r) => r.parse::<f64>().ok()
+                            .and_then(serde_json::Number::from_f64)
+                            .map(serde_json::Value::Number)
+                            .unwrap_or(serde_json::Value::Null),
+        Yaml::Integer(i) => serde_json::Value::from(*i),
+        Yaml::String(s) => serde_json::Value::from(s.clone()),
+        Yaml::Boolean(b) => serde_json::Value::from(*b),
+        Yaml::Array(a) => serde_json::Value::Array(a.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(h) => serde_json::Value::Object(h.iter().map(|(k, v)| {
+            let key = match k {
+                Yaml::String(s) | Yaml::Real(s) => s.clone(),
+                Yaml::Integer(i) => i.to_string(),
+                Yaml::Boolean(b) => b.to_string(),
+                _ => format!("{:?}", k),
+            };
+            (key, yaml_to_json(v))
+        }).collect()),
+        _ => serde_json::Value::Null,
+    }
+}
 
 fn generate_cfg(cfg_path: &str) -> Result<(), String>{
     let plugin_default = r#"---
 plugins:
     vehicle_status:
         path: libvehicle_status.so
-        active: false
+        desired: stopped
+        restart: on-failure
     traffic_light:
         path: libtraffic_light.so
-        active: false"#;
+        desired: stopped
+        restart: on-failure"#;
 
     let docs = YamlLoader::load_from_str(&plugin_default).map_err(|e| format!("Generate RSU config failed: {:?}", e))?;
     let doc = &docs[0];
@@ -300,3 +1905,182 @@ plugins:
     fs::write(&cfg_path, writer).map_err(|e| format!("Generate RSU config, write failed: {:?}", e))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signing::SignaturePolicy;
+
+    fn plugins_yaml(doc: &str) -> Yaml {
+        YamlLoader::load_from_str(doc).unwrap().remove(0)
+    }
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rsu-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        String::from(dir.to_str().unwrap())
+    }
+
+    #[test]
+    fn restore_library_puts_back_the_signature() {
+        let dir = temp_dir("restore");
+        let (backup, file) = (format!("{}/backup.so", dir), format!("{}/libp.so", dir));
+        fs::write(&backup, "old").unwrap();
+        fs::write(format!("{}.sig", backup), "old sig").unwrap();
+        fs::write(&file, "new").unwrap();
+        fs::write(format!("{}.sig", file), "new sig").unwrap();
+
+        restore_library(&backup, &file).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "old");
+        assert_eq!(fs::read_to_string(format!("{}.sig", file)).unwrap(), "old sig");
+        assert!(!Path::new(&format!("{}.rollback", file)).exists());
+
+        // an unsigned build does not keep the signature of the failed one
+        fs::remove_file(format!("{}.sig", backup)).unwrap();
+        restore_library(&backup, &file).unwrap();
+        assert!(!Path::new(&format!("{}.sig", file)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn plugin_cfg(plugins: &[(&str, &[&str])]) -> HashMap<String, PluginInfo> {
+        plugins.iter().map(|(name, deps)| {
+            let mut plugin_info = PluginInfo::new(&format!("lib{}.so", name), DesiredState::Running);
+            plugin_info.depends_on = deps.iter().map(|d| String::from(*d)).collect();
+            (String::from(*name), plugin_info)
+        }).collect()
+    }
+
+    #[test]
+    fn dependency_order_puts_dependencies_first() {
+        let order = dependency_order(&plugin_cfg(&[("c", &["b"]), ("b", &["a"]), ("a", &[]), ("d", &[])])).unwrap();
+        assert_eq!(order, vec!["a", "d", "b", "c"]);
+    }
+
+    #[test]
+    fn dependency_order_rejects_cycles() {
+        let e = dependency_order(&plugin_cfg(&[("a", &["b"]), ("b", &["a"]), ("c", &[])])).unwrap_err();
+        assert_eq!(e, r#"plugin dependency cycle between: ["a", "b"]"#);
+        let e = dependency_order(&plugin_cfg(&[("a", &["a"])])).unwrap_err();
+        assert!(e.contains("cycle"), "{}", e);
+    }
+
+    #[test]
+    fn dependency_order_rejects_missing_dependencies() {
+        let e = dependency_order(&plugin_cfg(&[("a", &["missing"])])).unwrap_err();
+        assert_eq!(e, "plugin[a] depends on plugin[missing], which does not exist");
+    }
+
+    fn planned(current: &str, running: &[&str], wanted: &str) -> Vec<String> {
+        let current: HashMap<String, PluginInfo> = parse_plugins(&plugins_yaml(current)).unwrap().into_iter().collect();
+        let wanted: HashMap<String, PluginInfo> = parse_plugins(&plugins_yaml(wanted)).unwrap().into_iter().collect();
+        let order = dependency_order(&wanted).unwrap();
+        plan(&current, |name| running.contains(&name), &wanted, &order).iter().map(|change| match change {
+            Change::Remove {plugin} => format!("remove {}", plugin),
+            Change::Stop {plugin} => format!("stop {}", plugin),
+            Change::Add {plugin, path} => format!("add {} {}", plugin, path),
+            Change::Path {plugin, from, to} => format!("path {} {} -> {}", plugin, from, to),
+            Change::Update {plugin, fields} => format!("update {} {:?}", plugin, fields),
+            Change::Start {plugin} => format!("start {}", plugin),
+        }).collect()
+    }
+
+    const PLUGINS: &str = "a:\n  path: liba.so\n  desired: running\nb:\n  path: libb.so\n  desired: running\n  depends_on: [a]\n";
+
+    #[test]
+    fn plan_without_differences_is_empty() {
+        assert!(planned(PLUGINS, &["a", "b"], PLUGINS).is_empty());
+    }
+
+    #[test]
+    fn plan_removes_plugins_missing_from_the_document() {
+        let wanted = "a:\n  path: liba.so\n  desired: running\n";
+        assert_eq!(planned(PLUGINS, &["a", "b"], wanted), vec!["remove b"]);
+    }
+
+    #[test]
+    fn plan_adds_and_starts_new_plugins_after_their_dependencies() {
+        let wanted = format!("{}c:\n  path: libc.so\n  desired: running\n  depends_on: [b]\n", PLUGINS);
+        assert_eq!(planned(PLUGINS, &["a", "b"], &wanted), vec!["add c libc.so", "start c"]);
+        // b was not running, it is started before c
+        assert_eq!(planned(PLUGINS, &["a"], &wanted), vec!["add c libc.so", "start b", "start c"]);
+    }
+
+    #[test]
+    fn plan_reloads_a_running_plugin_from_a_new_path() {
+        let wanted = PLUGINS.replace("liba.so", "liba-2.so");
+        assert_eq!(planned(PLUGINS, &["a", "b"], &wanted), vec!["path a liba.so -> liba-2.so"]);
+    }
+
+    #[test]
+    fn plan_restarts_a_running_plugin_for_a_new_config() {
+        let wanted = format!("{}  config:\n    port: 8080\n", PLUGINS);
+        assert_eq!(planned(PLUGINS, &["a", "b"], &wanted), vec!["stop b", "update b [\"config\"]", "start b"]);
+        // settings that are read when the plugin is checked do not restart it
+        let wanted = format!("{}  max_retries: 9\n", PLUGINS);
+        assert_eq!(planned(PLUGINS, &["a", "b"], &wanted), vec!["update b [\"max_retries\"]"]);
+    }
+
+    #[test]
+    fn plan_stops_plugins_that_are_no_longer_wanted_running() {
+        let wanted = PLUGINS.replace("libb.so\n  desired: running", "libb.so\n  desired: stopped");
+        assert_eq!(planned(PLUGINS, &["a", "b"], &wanted), vec!["stop b"]);
+        // a plugin waiting for its restart is stopped as well, so the restart is cancelled
+        assert_eq!(planned(PLUGINS, &["a"], &wanted), vec!["stop b"]);
+    }
+
+    #[test]
+    fn failed_starts_at_boot_are_retried() {
+        let dir = temp_dir("boot");
+        let cfg_path = format!("{}/plugins.yaml", dir);
+        fs::write(&cfg_path, format!("plugins:\n  \
+            a:\n    path: {dir}/liba.so\n    desired: running\n    restart: on-failure\n  \
+            b:\n    path: {dir}/libb.so\n    desired: running\n    depends_on: [a]\n", dir = dir)).unwrap();
+        fs::write(format!("{}/liba.so", dir), "not a library").unwrap();
+        fs::write(format!("{}/libb.so", dir), "not a library").unwrap();
+        let library = LibraryPolicy::new(&[dir.clone()], SignaturePolicy::from_yaml(&Yaml::BadValue).unwrap()).unwrap();
+
+        let pm = PluginMgr::new(&cfg_path, library).unwrap();
+        // the failed start is retried, the dependent starts once its dependency runs
+        let a = &pm.plugin_cfg["a"];
+        assert_eq!(a.state, PluginState::Restarting);
+        assert!(a.restarts.next_restart.is_some());
+        let b = &pm.plugin_cfg["b"];
+        assert_eq!(b.state, PluginState::Stopped);
+        assert_eq!(b.desired, DesiredState::Running);
+        assert!(b.restarts.next_restart.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_plugins_reads_counts() {
+        let cfg = plugins_yaml("a:\n  path: /opt/liba.so\n  desired: running\n  max_retries: 3\n  backoff: 2\n  stop_timeout: 7\n");
+        let plugins = parse_plugins(&cfg).unwrap();
+        assert_eq!(plugins[0].1.max_retries, 3);
+        assert_eq!(plugins[0].1.backoff, 2);
+        assert_eq!(plugins[0].1.stop_timeout, 7);
+    }
+
+    #[test]
+    fn parse_plugins_rejects_negative_counts() {
+        for key in &["max_retries", "backoff", "stop_timeout"] {
+            let cfg = plugins_yaml(&format!("a:\n  path: /opt/liba.so\n  desired: running\n  {}: -1\n", key));
+            let e = parse_plugins(&cfg).unwrap_err();
+            assert!(e.contains(key), "{}", e);
+        }
+    }
+
+    #[test]
+    fn parse_plugins_rejects_reserved_name() {
+        let cfg = plugins_yaml(&format!("{}:\n  path: /opt/liba.so\n  desired: running\n", uplink::HOST_QUEUE));
+        let e = parse_plugins(&cfg).unwrap_err();
+        assert!(e.contains("reserved"), "{}", e);
+    }
+
+    #[test]
+    fn parse_plugins_rejects_zero_stop_timeout() {
+        let cfg = plugins_yaml("a:\n  path: /opt/liba.so\n  desired: running\n  stop_timeout: 0\n");
+        let e = parse_plugins(&cfg).unwrap_err();
+        assert!(e.contains("stop_timeout"), "{}", e);
+    }
+}
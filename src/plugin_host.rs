@@ -0,0 +1,389 @@
+use log::{info, debug, error};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::library::CheckedLibrary;
+use crate::plugin::{LocalPlugin, StopError};
+use crate::uplink::{self, Report};
+
+// how long the child process has to answer a command
+const IPC_TIMEOUT: Duration = Duration::from_secs(5);
+// how often the parent asks the child for its health, the reports of the plugin ride along
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+type HostStream = Arc<Mutex<BufReader<UnixStream>>>;
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum HostCommand {
+    // yaml config document of the plugin
+    Start(String),
+    // stop timeout in milliseconds
+    Stop(u64),
+    Health,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct HostReply {
+    ok: bool,
+    message: String,
+    // the plugin set its error flag
+    failed: bool,
+    // the plugin `run` has returned
+    exited: bool,
+    // reports of the plugin since the last reply, the parent hands them to its uplink
+    #[serde(default)]
+    reports: Vec<Report>,
+}
+
+// Last answer of the child to a health request, kept by the health thread
+#[derive(Debug, Default)]
+struct HostHealth {
+    failed: bool,
+    exited: bool,
+    // the child did not answer, the health thread gave up
+    error: Option<String>,
+}
+
+// Parent side of a plugin running in a `rsu plugin-host` child process. The child inherits one end
+// of a socket pair, nothing else can connect to it.
+// A health thread polls the child, so `check` never waits for it while the manager is locked.
+#[derive(Debug)]
+pub struct ProcessPlugin {
+    // name the manager knows the plugin by, the reports of the child are queued under it
+    name: String,
+    path: String,
+    // the private copy the child loads
+    library: CheckedLibrary,
+    child: Option<Child>,
+    stream: Option<HostStream>,
+    health: Arc<Mutex<HostHealth>>,
+    // dropped to end the health thread
+    health_stop: Option<mpsc::Sender<()>>,
+    health_thread: Option<JoinHandle<()>>,
+    exited: bool,
+    started_at: Option<Instant>,
+}
+
+impl ProcessPlugin {
+    pub fn new(name: &str, library: CheckedLibrary) -> Result<ProcessPlugin, String> {
+        Ok(ProcessPlugin {
+            name: String::from(name),
+            path: library.path.clone(),
+            library,
+            child: None,
+            stream: None,
+            health: Arc::new(Mutex::new(HostHealth::default())),
+            health_stop: None,
+            health_thread: None,
+            exited: false,
+            started_at: None,
+        })
+    }
+
+    pub(crate) fn start(&mut self, config: &str) -> Result<(), String> {
+        let (stream, child_stream) = UnixStream::pair()
+            .map_err(|e| format!("create plugin host socket failed: {:?}", e))?;
+        let child_fd = child_stream.as_raw_fd();
+
+        let exe = env::current_exe().map_err(|e| format!("get rsu executable failed: {:?}", e))?;
+        let mut command = Command::new(exe);
+        command.arg("plugin-host")
+            .arg(&self.name)
+            .arg(&self.library.copy)
+            .arg(child_fd.to_string());
+        // std opens every fd close-on-exec, only the child that runs this plugin keeps its end
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(child_fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().map_err(|e| format!("spawn plugin host for {} failed: {:?}", self.path, e))?;
+        // the stream reports the child closing once it is its only owner
+        drop(child_stream);
+        debug!("plugin host for {} started, pid: {}", self.path, child.id());
+        self.child = Some(child);
+
+        stream.set_read_timeout(Some(IPC_TIMEOUT)).map_err(|e| format!("set plugin host timeout failed: {:?}", e))?;
+        self.stream = Some(Arc::new(Mutex::new(BufReader::new(stream))));
+
+        match self.request(HostCommand::Start(String::from(config))) {
+            Ok(reply) if reply.ok => {
+                self.started_at = Some(Instant::now());
+                self.poll_health();
+                Ok(())
+            },
+            Ok(reply) => {
+                self.kill();
+                Err(reply.message)
+            },
+            Err(e) => {
+                self.kill();
+                Err(e)
+            }
+        }
+    }
+
+    // A plugin host that does not answer before `timeout` is killed,
+    // so an out-of-process plugin never ends up stuck.
+    pub(crate) fn stop(&mut self, timeout: Duration) -> Result<i32, StopError> {
+        self.stop_health();
+        if let Some(stream) = self.stream.as_ref() {
+            if let Err(e) = stream.lock().unwrap().get_ref().set_read_timeout(Some(timeout + IPC_TIMEOUT)) {
+                debug!("set plugin host timeout failed: {:?}", e);
+            }
+        }
+        let ret = self.request(HostCommand::Stop(timeout.as_millis() as u64));
+        self.stream = None;
+        match self.child.take() {
+            Some(mut child) => {
+                // the child exits by itself once the plugin stopped
+                if let Err(e) = &ret {
+                    error!("stop plugin host for {} failed, kill it: {}", self.path, e);
+                    let _ = child.kill();
+                }
+                let status = child.wait().map_err(|e| StopError::Failed(format!("wait plugin host failed: {:?}", e)))?;
+                debug!("plugin host for {} exited: {}", self.path, status);
+            },
+            None => {
+                return Err(StopError::Failed(String::from("plugin host is not running")))
+            }
+        }
+        let reply = ret.map_err(StopError::Failed)?;
+        if reply.ok {
+            Ok(0)
+        } else {
+            Err(StopError::Failed(reply.message))
+        }
+    }
+
+    // Only looks at the exit status of the child and the last answer to the health thread,
+    // it is called with the manager locked
+    pub(crate) fn check(&mut self) -> Result<(), String> {
+        if let Some(status) = self.child_status() {
+            self.exited = true;
+            return Err(format!("plugin host process exited: {}", status))
+        }
+        let health = self.health.lock().unwrap();
+        if let Some(e) = &health.error {
+            return Err(e.clone())
+        }
+        self.exited = health.exited;
+        if health.failed {
+            error!("running plugin error");
+            return Err(String::from("running plugin error"))
+        }
+        Ok(())
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub(crate) fn exited(&self) -> bool {
+        self.exited
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started_at.map(|t| t.elapsed()).unwrap_or_default()
+    }
+
+    fn request(&self, cmd: HostCommand) -> Result<HostReply, String> {
+        let stream = self.stream.as_ref().ok_or(format!("plugin host for {} is not connected", self.path))?;
+        exchange(stream, &self.name, &self.path, cmd)
+    }
+
+    // ask the child for its health every HEALTH_INTERVAL until `stop_health`
+    fn poll_health(&mut self) {
+        let stream = match self.stream.as_ref() {
+            Some(s) => Arc::clone(s),
+            None => return,
+        };
+        self.health = Arc::new(Mutex::new(HostHealth::default()));
+        let health = Arc::clone(&self.health);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (name, path) = (self.name.clone(), self.path.clone());
+        self.health_stop = Some(stop);
+        self.health_thread = Some(thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(HEALTH_INTERVAL) {
+                let ret = exchange(&stream, &name, &path, HostCommand::Health);
+                let mut health = health.lock().unwrap();
+                match ret {
+                    Ok(reply) => {
+                        health.failed = reply.failed;
+                        health.exited = reply.exited;
+                    },
+                    Err(e) => {
+                        error!("health check of plugin host for {} failed: {}", path, e);
+                        health.error = Some(e);
+                        return
+                    }
+                }
+            }
+        }));
+    }
+
+    fn stop_health(&mut self) {
+        self.health_stop = None;
+        if let Some(handle) = self.health_thread.take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn child_status(&mut self) -> Option<String> {
+        match self.child.as_mut().map(|c| c.try_wait()) {
+            Some(Ok(Some(status))) => Some(format!("{}", status)),
+            Some(Err(e)) => Some(format!("{:?}", e)),
+            _ => None,
+        }
+    }
+
+    fn kill(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+        }
+        self.cleanup();
+    }
+
+    fn cleanup(&mut self) {
+        self.stop_health();
+        if let Some(mut child) = self.child.take() {
+            let _ = child.wait();
+        }
+        self.stream = None;
+    }
+}
+
+impl Drop for ProcessPlugin {
+    fn drop(&mut self) {
+        if self.child.is_some() {
+            self.kill();
+        }
+    }
+}
+
+// Send `cmd` to the child and read its reply, the reports riding along are queued under `name`
+fn exchange(stream: &Mutex<BufReader<UnixStream>>, name: &str, path: &str, cmd: HostCommand) -> Result<HostReply, String> {
+    let mut stream = stream.lock().unwrap();
+    send_line(stream.get_mut(), &cmd)?;
+    let mut line = String::new();
+    let len = stream.read_line(&mut line).map_err(|e| format!("read plugin host reply failed: {:?}", e))?;
+    if len == 0 {
+        return Err(format!("plugin host for {} closed the connection", path))
+    }
+    let mut reply: HostReply = serde_json::from_str(&line).map_err(|e| format!("parse plugin host reply failed: {:?}", e))?;
+    // the child only runs this plugin, whatever source it claims
+    for report in reply.reports.drain(..) {
+        if let Err(e) = uplink::submit(name, &report.topic, report.body) {
+            error!("queue report of plugin host for {} failed: {}", path, e);
+        }
+    }
+    Ok(reply)
+}
+
+fn send_line<T: Serialize>(stream: &mut UnixStream, msg: &T) -> Result<(), String> {
+    let mut line = serde_json::to_string(msg).map_err(|e| format!("serialize plugin host message failed: {:?}", e))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(|e| format!("write plugin host message failed: {:?}", e))
+}
+
+// Entry of `rsu plugin-host <name> <path> <fd>`, runs one plugin on behalf of the parent rsu,
+// `fd` is the inherited end of the socket pair
+pub fn run_plugin_host(name: &str, path: &str, fd: &str) -> i32 {
+    let fd = match fd.parse::<RawFd>() {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("plugin host socket fd {} is invalid: {:?}", fd, e);
+            return -1
+        }
+    };
+    // the parent hands over the fd, nothing else owns it
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("plugin host clone stream failed: {:?}", e);
+            return -1
+        }
+    };
+
+    // the parent owns the uplink, reports ride along with the replies
+    uplink::relay();
+    let mut plugin = LocalPlugin::new(name, path, path);
+    let mut running = false;
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                error!("plugin host read command failed: {:?}", e);
+                break
+            }
+        };
+        let cmd: HostCommand = match serde_json::from_str(&line) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("plugin host parse command failed: {:?}", e);
+                break
+            }
+        };
+        debug!("plugin host received command: {:?}", cmd);
+
+        let mut reply = HostReply::default();
+        match (&mut plugin, cmd) {
+            (Err(e), _) => {
+                reply.message = e.clone();
+            },
+            (Ok(p), HostCommand::Start(config)) => {
+                match p.start(&config) {
+                    Ok(_) => {
+                        running = true;
+                        reply.ok = true;
+                    },
+                    Err(e) => reply.message = e,
+                }
+            },
+            (Ok(p), HostCommand::Health) => {
+                reply.ok = true;
+                reply.failed = p.check().is_err();
+                reply.exited = p.exited();
+            },
+            (Ok(p), HostCommand::Stop(timeout)) => {
+                // exiting the process also ends a plugin that is stuck
+                match p.stop(Duration::from_millis(timeout)) {
+                    Ok(ret) => {
+                        reply.ok = true;
+                        reply.message = format!("plugin stopped: {}", ret);
+                    },
+                    Err(e) => reply.message = e.to_string(),
+                }
+                reply.reports = uplink::take_relayed();
+                let _ = send_line(&mut writer, &reply);
+                info!("plugin host for {} stopped", path);
+                return 0
+            },
+        }
+        reply.reports = uplink::take_relayed();
+        if let Err(e) = send_line(&mut writer, &reply) {
+            error!("plugin host reply failed: {}", e);
+            break
+        }
+    }
+
+    // the parent went away, do not leave the plugin running
+    if let Ok(p) = plugin.as_mut() {
+        if running {
+            let _ = p.stop(IPC_TIMEOUT);
+        }
+    }
+    -1
+}
@@ -1,29 +1,62 @@
 use log::{info, error, debug};
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
-use tide::{Request, Response};
+use tide::{Endpoint, Request, Response};
+use tide::sse::Sender;
 use tide::prelude::*;
 use tide::utils::{After};
-use serde_json::{Result, Value};
-use percent_encoding::{percent_decode};
+use serde_json::Value;
 use tokio::time::Instant;
-use reqwest;
+use async_std::task;
 extern crate lazy_static;
 use lazy_static::lazy_static;
 use crate::plugin;
-use plugin::{PluginMgr};
+use plugin::{ChangeReport, DesiredState, Plugin, PluginError, PluginMgr, ReloadJob, StopError};
+use crate::api::{self, ActivateRequest, AddRequest, ApiError, ErrorCode, ReloadRequest, RemoveRequest, UploadRequest};
+use crate::auth::{Auth, Role};
+use crate::package::PluginStore;
+use crate::events::{self, EventKind};
+use crate::metrics::{self, HttpMetrics};
+use tokio::sync::broadcast::error::RecvError;
+use async_std::io::ReadExt;
+use tide_rustls::TlsListener;
+use yaml_rust::YamlLoader;
+use crate::uplink;
 
 
+// how long a reloaded plugin has to run without error before the reload is accepted
+const RELOAD_GRACE_SECS: u64 = 3;
+// the longest grace a reload may ask for, the plugin can not be stopped or reloaded meanwhile
+const MAX_RELOAD_GRACE_SECS: u64 = 60;
+// largest plugin library accepted by /plugin/upload
+const MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024;
+// an idle event stream sends a ping this often, so proxies keep it open and dead clients are noticed
+const EVENT_PING_SECS: u64 = 15;
+
+// shared by the request handlers
+pub struct ServerState {
+    auth: Auth,
+    store: PluginStore,
+    // spool dir of the upload queues, their depth is exported on /metrics
+    outbox_dir: String,
+}
+
+type State = Arc<ServerState>;
+
 lazy_static! {
-    pub static ref PM: Arc<Mutex<Result<PluginMgr>>> = {
-        let cfg_path = "./config/plugins.yaml";
-        let pm = PluginMgr::new(&cfg_path).unwrap();
-        Arc::new(Mutex::new(Ok(pm)))
-    };
+    // set by main once rsu.yaml is read, the plugins are loaded under its signing policy
+    pub static ref PM: Arc<Mutex<Option<PluginMgr>>> = Arc::new(Mutex::new(None));
 }
 
-pub async fn server(port: String) -> tide::Result<()> {
-    let mut app = tide::new();
+pub async fn server(port: String, auth: Auth, store: PluginStore, outbox_dir: String) -> tide::Result<()> {
+    let tls = match auth.tls_config() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("start RSU server failed: {}", e);
+            return Err(tide::Error::from_str(tide::StatusCode::InternalServerError, e))
+        }
+    };
+    let mut app = tide::with_state(Arc::new(ServerState {auth, store, outbox_dir}));
 
     app.at("/").get(|_| async { Ok("RSU OK") });
 
@@ -31,138 +64,435 @@ pub async fn server(port: String) -> tide::Result<()> {
         res.insert_header("Access-Control-Allow-Origin", "*");
         Ok(res)
     }));
+    app.with(HttpMetrics);
 
-    app.at("/plugin").post(|mut req: Request<()>| async move {
-        let plugin = match req.body_string().await {
-            Ok(p) => p,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("{:?}", e)}))
-        };
-        let plugin_decoded = percent_decode(plugin.as_bytes()).decode_utf8()?;
-        let plugin_obj: Value = match serde_json::from_str(&plugin_decoded) {
-            Ok(v) => v,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("param parse into json wrong: {:?}", e)}))
-        };
-        info!("received update plugin message: {:?}", plugin_decoded);
+    app.at("/metrics").get(|req: Request<State>| async move {
+        Ok(match plugin_metrics(&req).await {
+            Ok(text) => Response::builder(tide::StatusCode::Ok).body(text).content_type("text/plain; version=0.0.4").build(),
+            Err(e) => e.into_response(),
+        })
+    });
+
+    app.at("/plugins").get(|req: Request<State>| async move {
+        Ok(api::respond(list_plugins(&req).await))
+    }).put(|mut req: Request<State>| async move {
+        let ret = put_plugins(&mut req).await;
+        Ok(audited("/plugins", &req, ret))
+    });
+
+    app.at("/plugin/:name").get(|req: Request<State>| async move {
+        Ok(api::respond(get_plugin(&req).await))
+    });
 
-        if !plugin_obj.is_object() {
-            return Ok(json!({ "status": -1, "message": "params are wrong, ex: {\"name\": \"traffic_light\", \"active\": true}"}))
+    app.at("/events").get(|req: Request<State>| async move {
+        if let Err(e) = req.state().auth.require(&req, Role::Viewer) {
+            return Ok(e.into_response())
         }
+        tide::sse::endpoint(stream_events).call(req).await
+    });
 
-        let name = match plugin_obj.get("name") {
-            Some(na) => na.as_str().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: name"}))
-        };
+    app.at("/plugin").post(|mut req: Request<State>| async move {
+        let ret = activate_plugin(&mut req).await;
+        Ok(audited("/plugin", &req, ret))
+    });
 
-        let active = match plugin_obj.get("active") {
-            Some(ac) => ac.as_bool().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: active"}))
-        };
+    app.at("/plugin/remove").post(|mut req: Request<State>| async move {
+        let ret = remove_plugin(&mut req).await;
+        Ok(audited("/plugin/remove", &req, ret))
+    });
 
-        debug!("update plugin state ...");
-        {
-            let mut pm_locked = PM.lock().unwrap();
-            let pm = pm_locked.as_mut().unwrap();
-            if active {
-                match pm.start_plugin(&name){
-                    Ok(res) => return Ok(json!({ "status": 1, "message": res})),
-                    Err(err) => return Ok(json!({ "status": -1, "message": err})),
-                };
-            } else {
-                debug!("begin to stop plugin {}", name);
-                match pm.stop_plugin(&name) {
-                    Ok(res) => return Ok(json!({ "status": 1, "message": res})),
-                    Err(err) => return Ok(json!({ "status": -1, "message": err})),
-                };
-            }
-        }
-        
+    app.at("/plugin/reload").post(|mut req: Request<State>| async move {
+        let ret = reload_plugin(&mut req).await;
+        Ok(audited("/plugin/reload", &req, ret))
     });
-    
-    app.at("/plugin/remove").post(|mut req: Request<()>| async move {
-        let plugin = match req.body_string().await {
-            Ok(p) => p,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("{:?}", e)}))
-        };
-        let plugin_decoded = percent_decode(plugin.as_bytes()).decode_utf8()?;
-        let plugin_obj: Value = match serde_json::from_str(&plugin_decoded) {
-            Ok(v) => v,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("param parse into json wrong: {:?}", e)}))
-        };
 
-        if !plugin_obj.is_object() {
-            return Ok(json!({ "status": -1, "message": "params are wrong, ex: {\"name\": \"traffic_light\"}"}))
+    app.at("/plugin/add").post(|mut req: Request<State>| async move {
+        let ret = add_plugin(&mut req).await;
+        Ok(audited("/plugin/add", &req, ret))
+    });
+
+    app.at("/plugin/upload").post(|mut req: Request<State>| async move {
+        let ret = upload_plugin(&mut req).await;
+        Ok(audited("/plugin/upload", &req, ret))
+    });
+    info!("start RSU server ......");
+    let addr = format!("0.0.0.0:{}", port);
+    match tls {
+        Some(config) => app.listen(TlsListener::build().addrs(addr).config(config)).await?,
+        None => app.listen(addr).await?,
+    }
+    Ok(())
+}
+
+// Respond to a call that changes something, the outcome is reported on the event stream
+fn audited(action: &str, req: &Request<State>, ret: std::result::Result<Value, ApiError>) -> Response {
+    let (ok, message) = match &ret {
+        Ok(body) => (true, String::from(body["message"].as_str().unwrap_or_default())),
+        Err(e) => (false, e.message.clone()),
+    };
+    events::publish(EventKind::Api {action: String::from(action), remote: req.remote().map(String::from), ok, message});
+    api::respond(ret)
+}
+
+// Push events as they happen. A client reconnecting with Last-Event-ID first gets the kept events it missed.
+async fn stream_events(req: Request<State>, sender: Sender) -> tide::Result<()> {
+    let last_seq = req.header("Last-Event-ID").and_then(|h| h.last().as_str().parse::<u64>().ok());
+    let (missed, mut receiver) = events::subscribe(last_seq);
+    for event in missed {
+        sender.send(event.kind.name(), serde_json::to_string(&event)?, Some(&event.seq.to_string())).await?;
+    }
+    loop {
+        match async_std::future::timeout(Duration::from_secs(EVENT_PING_SECS), receiver.recv()).await {
+            Ok(Ok(event)) => sender.send(event.kind.name(), serde_json::to_string(&event)?, Some(&event.seq.to_string())).await?,
+            Ok(Err(RecvError::Lagged(missed))) => {
+                sender.send("lagged", json!({ "missed": missed }).to_string(), None).await?
+            },
+            Ok(Err(RecvError::Closed)) => return Ok(()),
+            Err(_) => sender.send("ping", "", None).await?,
         }
+    }
+}
+
+// The read handlers lock the plugin manager as well, which may be held by a start or a reload
+async fn plugin_metrics(req: &Request<State>) -> std::result::Result<String, ApiError> {
+    req.state().auth.require(req, Role::Viewer)?;
+    let outbox_dir = req.state().outbox_dir.clone();
+    task::spawn_blocking(move || {
+        let pm_locked = PM.lock().unwrap();
+        let pm = pm_locked.as_ref().unwrap();
+        metrics::render(pm, &outbox_dir).map_err(|e| ApiError::new(ErrorCode::Internal, e))
+    }).await
+}
 
-        let name = match plugin_obj.get("name") {
-            Some(na) => na.as_str().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: name"}))
+async fn list_plugins(req: &Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Viewer)?;
+    task::spawn_blocking(|| {
+        let pm_locked = PM.lock().unwrap();
+        let pm = pm_locked.as_ref().unwrap();
+        Ok(json!({ "status": 1, "plugins": pm.plugin_views()}))
+    }).await
+}
+
+async fn get_plugin(req: &Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Viewer)?;
+    let name = String::from(req.param("name").map_err(|e| ApiError::bad_request(format!("need param: name, {}", e)))?);
+    task::spawn_blocking(move || {
+        let pm_locked = PM.lock().unwrap();
+        let pm = pm_locked.as_ref().unwrap();
+        match pm.plugin_view(&name, true) {
+            Some(view) => Ok(json!({ "status": 1, "plugin": view})),
+            None => Err(ApiError::new(ErrorCode::NotFound, format!("plugin[{}] does not exist", name))),
+        }
+    }).await
+}
+
+// Reconcile the plugins with a whole desired plugins document, `?dry_run=true` only returns the plan
+async fn put_plugins(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Admin)?;
+    let dry_run = req.url().query_pairs().any(|(k, v)| k == "dry_run" && (v == "true" || v == "1"));
+    let body = req.body_string().await
+                    .map_err(|e| ApiError::bad_request(format!("read request body failed: {}", e)))?;
+    task::spawn_blocking(move || reconcile(&body, dry_run)).await
+}
+
+// `body` is a yaml or json document with a `plugins` mapping
+pub(crate) fn reconcile(body: &str, dry_run: bool) -> std::result::Result<Value, ApiError> {
+    // json is yaml as well
+    let docs = YamlLoader::load_from_str(body)
+                    .map_err(|e| ApiError::bad_request(format!("request body is neither yaml nor json: {}", e)))?;
+    let doc = docs.get(0).ok_or_else(|| ApiError::bad_request(String::from("request body is empty")))?;
+    info!("received desired plugins, dry run: {}", dry_run);
+
+    let grace = Duration::from_secs(RELOAD_GRACE_SECS);
+    let plan = {
+        let pm_locked = PM.lock().unwrap();
+        pm_locked.as_ref().unwrap().plan_reconcile(&doc["plugins"])?
+    };
+    let reports = if dry_run {
+        plan.changes.iter().cloned().map(ChangeReport::planned).collect()
+    } else {
+        apply_reconcile(&plan, grace)?
+    };
+    let failed = reports.iter().filter(|r| r.failed()).count();
+    if failed > 0 {
+        return Err(ApiError::new(ErrorCode::Internal, format!("{} of {} changes failed", failed, reports.len()))
+                    .with_details(json!(reports)))
+    }
+    let message = format!("{} changes {}", reports.len(), if dry_run { "planned" } else { "applied" });
+    Ok(json!({ "status": 1, "message": message, "dry_run": dry_run, "changes": reports}))
+}
+
+// the plugins of removals and stops are stopped without holding the PM lock, the starts come last
+fn apply_reconcile(plan: &plugin::Reconcile, grace: Duration) -> std::result::Result<Vec<ChangeReport>, PluginError> {
+    info!("reconcile plugins, {} changes planned", plan.changes.len());
+    let mut reports: Vec<ChangeReport> = vec![];
+    for change in plan.changes.iter().filter(|c| !matches!(c, plugin::Change::Start {..})) {
+        let unlocked = match change {
+            plugin::Change::Remove {plugin} | plugin::Change::Stop {plugin} => stop_plugin(plugin).map(|_| ()),
+            plugin::Change::Path {plugin, to, ..} => {
+                reports.push(ChangeReport::applied(change.clone(), upgrade(plugin, to, grace).map(|_| ())));
+                continue;
+            },
+            _ => Ok(()),
         };
-        
+        let ret = unlocked.and_then(|_| {
+            let mut pm_locked = PM.lock().unwrap();
+            pm_locked.as_mut().unwrap().apply_change(change, plan)
+        });
+        reports.push(ChangeReport::applied(change.clone(), ret));
+    }
+    let mut pm_locked = PM.lock().unwrap();
+    let pm = pm_locked.as_mut().unwrap();
+    reports.append(&mut pm.finish_reconcile(plan)?);
+    Ok(reports)
+}
+
+async fn activate_plugin(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Operator)?;
+    let body: ActivateRequest = api::parse_body(req).await?;
+    task::spawn_blocking(move || activate(body)).await
+}
+
+// Start or stop a plugin, the operations below are shared by the API and the command channel.
+// They block on the PM lock and on plugins starting or stopping, run them on a blocking thread.
+pub(crate) fn activate(body: ActivateRequest) -> std::result::Result<Value, ApiError> {
+    info!("received update plugin message: {:?}", body);
+
+    debug!("update plugin state ...");
+    if body.active {
         let mut pm_locked = PM.lock().unwrap();
         let pm = pm_locked.as_mut().unwrap();
-        match pm.remove_plugin(&name) {
-            Ok(_) => return Ok(json!({ "status": 1, "message": format!("remove plugin {} successful", name)})),
-            Err(e) => return Ok(json!({ "status": 1, "message": format!("remove plugin {} failed, error: {:?}", name, e)})),
+        let res = pm.start_plugin(&body.name)?;
+        return Ok(api::ok_message(res))
+    }
+
+    // the plugin is stopped without holding the PM lock, a slow plugin does not block the other APIs
+    Ok(api::ok_message(stop_plugin(&body.name)?))
+}
+
+// Stop a plugin and its running dependents, blocks until they stopped or timed out
+fn stop_plugin(name: &str) -> std::result::Result<String, PluginError> {
+    let detached = {
+        let mut pm_locked = PM.lock().unwrap();
+        pm_locked.as_mut().unwrap().detach_plugin(name)?
+    };
+    if detached.is_empty() {
+        return Ok(format!("plugin[{}] is not running", name))
+    }
+    stop_detached(detached)
+}
+
+// Stop plugins `detach_plugin` took out of the manager, without holding the PM lock. Dependents
+// come first, once one of them is stuck the rest is put back running.
+fn stop_detached(detached: Vec<(String, Plugin, Duration)>) -> std::result::Result<String, PluginError> {
+    let mut stopped = vec![];
+    let mut rest = vec![];
+    for (plugin_name, mut plugin, timeout) in detached {
+        if stopped.iter().any(|(_, _, ret)| matches!(ret, Err(StopError::Stuck(_)))) {
+            rest.push((plugin_name, plugin));
+            continue;
         }
-        
-    });
+        let ret = plugin.stop(timeout);
+        stopped.push((plugin_name, plugin, ret));
+    }
 
-    app.at("/plugin/add").post(|mut req: Request<()>| async move {
-        let plugin = match req.body_string().await {
-            Ok(p) => p,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("param parse into json wrong: {:?}", e)}))
-        };
-        let plugin_decoded = percent_decode(plugin.as_bytes()).decode_utf8()?;
-        let plugin_obj: Value = match serde_json::from_str(&plugin_decoded) {
-            Ok(v) => v,
-            Err(e) => return Ok(json!({ "status": -1, "message": format!("{:?}", e)}))
-        };
+    let mut pm_locked = PM.lock().unwrap();
+    let pm = pm_locked.as_mut().unwrap();
+    for (plugin_name, plugin) in rest {
+        pm.reattach_plugin(&plugin_name, plugin);
+    }
+    let mut messages: Vec<String> = vec![];
+    let mut failed = false;
+    for (plugin_name, plugin, ret) in stopped {
+        match pm.finish_stop(&plugin_name, plugin, ret) {
+            Ok(res) => messages.push(res),
+            Err(err) => {
+                failed = true;
+                messages.push(err.to_string());
+            }
+        }
+    }
+    if failed {
+        return Err(PluginError::Failed(messages.join(", ")))
+    }
+    Ok(messages.join(", "))
+}
 
-        if !plugin_obj.is_object() {
-            return Ok(json!({ "status": -1, "message": "params are wrong, ex: {\"name\": \"traffic_light\", \"path\": \"/home/traffic_light\", \"active\": true}"}))
+// Stop every plugin when rsu exits, dependents first
+pub fn stop_all() {
+    let detached = {
+        let mut pm_locked = PM.lock().unwrap();
+        pm_locked.as_mut().unwrap().detach_all()
+    };
+    for (name, mut plugin, timeout) in detached {
+        let ret = plugin.stop(timeout);
+        let mut pm_locked = PM.lock().unwrap();
+        if let Err(e) = pm_locked.as_mut().unwrap().finish_stop(&name, plugin, ret) {
+            error!("{}", e);
         }
+    }
+}
 
-        let name = match plugin_obj.get("name") {
-            Some(na) => na.as_str().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: name"}))
-        };
-        
-        let path = match plugin_obj.get("path") {
-            Some(pa) => pa.as_str().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: path"}))
-        };
-        
-        let active = match plugin_obj.get("active") {
-            Some(ac) => ac.as_bool().unwrap(),
-            None => return Ok(json!({ "status": -1, "message": "need param: active"}))
-        };
+async fn remove_plugin(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Admin)?;
+    let body: RemoveRequest = api::parse_body(req).await?;
+    task::spawn_blocking(move || remove(body)).await
+}
 
+pub(crate) fn remove(body: RemoveRequest) -> std::result::Result<Value, ApiError> {
+    let detached = {
         let mut pm_locked = PM.lock().unwrap();
         let pm = pm_locked.as_mut().unwrap();
-        match pm.add_plugin(&name, &path, active) {
-            Ok(_) => Ok(json!({ "status": 1, "message": format!("add plugin {} successful", name)})),
-            Err(e) => return Ok(json!({ "status": 1, "message": format!("add plugin {} failed, error: {:?}", name, e)})),
+        pm.check_removable(&body.name)?;
+        pm.detach_plugin(&body.name)?
+    };
+    if !detached.is_empty() {
+        stop_detached(detached)?;
+    }
+    let mut pm_locked = PM.lock().unwrap();
+    let pm = pm_locked.as_mut().unwrap();
+    pm.remove_plugin(&body.name)?;
+    Ok(api::ok_message(format!("remove plugin {} successful", body.name)))
+}
+
+async fn reload_plugin(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Operator)?;
+    let body: ReloadRequest = api::parse_body(req).await?;
+    info!("received reload plugin message: {:?}", body);
+    // loading a library from a new path is as powerful as adding a plugin
+    if body.path.is_some() {
+        req.state().auth.require(req, Role::Admin)?;
+    }
+    task::spawn_blocking(move || reload(body)).await
+}
+
+pub(crate) fn reload(body: ReloadRequest) -> std::result::Result<Value, ApiError> {
+    // path is optional, the plugin is reloaded from its current path by default
+    let grace = body.grace.unwrap_or(RELOAD_GRACE_SECS);
+    if grace > MAX_RELOAD_GRACE_SECS {
+        return Err(ApiError::bad_request(format!("grace {} is longer than {} seconds", grace, MAX_RELOAD_GRACE_SECS)))
+    }
+    let job = {
+        let mut pm_locked = PM.lock().unwrap();
+        pm_locked.as_mut().unwrap().begin_reload(&body.name, body.path.as_deref(), Duration::from_secs(grace))?
+    };
+    Ok(api::ok_message(run_reload(job)?))
+}
+
+// Point a plugin at a new library, a running plugin is reloaded from it with rollback
+fn upgrade(name: &str, path: &str, grace: Duration) -> std::result::Result<String, PluginError> {
+    let job = {
+        let mut pm_locked = PM.lock().unwrap();
+        let pm = pm_locked.as_mut().unwrap();
+        if !pm.is_running(name) {
+            return pm.upgrade_plugin(name, path)
         }
-        
-    });
-    info!("start RSU server ......");
-    app.listen(format!("0.0.0.0:{}", port)).await?;
-    Ok(())
+        pm.begin_reload(name, Some(path), grace)?
+    };
+    run_reload(job)
 }
 
+// The old plugin is stopped and the new build watched for its grace period without holding the PM lock
+fn run_reload(mut job: ReloadJob) -> std::result::Result<String, PluginError> {
+    let outcome = job.run();
+    let mut pm_locked = PM.lock().unwrap();
+    pm_locked.as_mut().unwrap().finish_reload(job, outcome)
+}
 
-// send plugin state
-pub async fn send(center_db_url: String, duration: u64) {
-    if center_db_url == "" {
-        error!("center_db_url is empty ......");
-        return
+async fn add_plugin(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Admin)?;
+    let body: AddRequest = api::parse_body(req).await?;
+    task::spawn_blocking(move || add(body)).await
+}
+
+pub(crate) fn add(body: AddRequest) -> std::result::Result<Value, ApiError> {
+    let desired = if body.active { DesiredState::Running } else { DesiredState::Stopped };
+    let mut pm_locked = PM.lock().unwrap();
+    let pm = pm_locked.as_mut().unwrap();
+    pm.add_plugin(&body.name, &body.path, desired, &body.depends_on)?;
+    Ok(api::ok_message(format!("add plugin {} successful", body.name)))
+}
+
+
+// Store an uploaded library in the managed plugin dir after checking its sha256, then add the plugin,
+// or point an existing plugin at the new version
+async fn upload_plugin(req: &mut Request<State>) -> std::result::Result<Value, ApiError> {
+    req.state().auth.require(req, Role::Admin)?;
+    let content_type = req.header("Content-Type").map(|h| h.last().as_str().to_string()).unwrap_or_default();
+    let boundary = multer::parse_boundary(&content_type)
+                        .map_err(|e| ApiError::bad_request(format!("expect a multipart/form-data body: {}", e)))?;
+
+    let mut data: Vec<u8> = vec![];
+    req.take_body().take(MAX_UPLOAD_SIZE + 1).read_to_end(&mut data).await
+        .map_err(|e| ApiError::bad_request(format!("read request body failed: {}", e)))?;
+    if data.len() as u64 > MAX_UPLOAD_SIZE {
+        return Err(ApiError::new(ErrorCode::TooLarge, format!("upload is larger than {} bytes", MAX_UPLOAD_SIZE)))
+    }
+
+    let stream = async_std::stream::once(Ok::<Vec<u8>, std::convert::Infallible>(data));
+    let mut multipart = multer::Multipart::new(stream, boundary);
+    let mut file: Option<Vec<u8>> = None;
+    let mut fields: Vec<(String, String)> = vec![];
+    while let Some(field) = multipart.next_field().await
+                                .map_err(|e| ApiError::bad_request(format!("read multipart body failed: {}", e)))? {
+        let field_name = field.name().map(String::from).unwrap_or_default();
+        if field_name == "file" {
+            let bytes = field.bytes().await.map_err(|e| ApiError::bad_request(format!("read field file failed: {}", e)))?;
+            file = Some(bytes.to_vec());
+        } else {
+            let text = field.text().await.map_err(|e| ApiError::bad_request(format!("read field {} failed: {}", field_name, e)))?;
+            fields.push((field_name, text));
+        }
     }
-    
+    let file = file.ok_or_else(|| ApiError::bad_request(String::from("need field: file")))?;
+    let body: UploadRequest = api::parse_fields(fields)?;
+    info!("received plugin upload, name: {}, version: {:?}, size: {}", body.name, body.version, file.len());
+
+    // checking and writing the library does not hold the PM lock
+    let state = req.state().clone();
+    let (name, version, sha256, signature) = (body.name.clone(), body.version.clone(), body.sha256.clone(), body.signature.clone());
+    let stored = task::spawn_blocking(move || {
+        state.store.store(&name, version.as_deref(), &file, &sha256, signature.as_deref())
+    }).await?;
+
+    let path = stored.path.clone();
+    let res = task::spawn_blocking(move || {
+        let exists = PM.lock().unwrap().as_ref().unwrap().plugin_cfg.contains_key(&body.name);
+        if exists {
+            return upgrade(&body.name, &path, Duration::from_secs(RELOAD_GRACE_SECS))
+        }
+        let desired = if body.active { DesiredState::Running } else { DesiredState::Stopped };
+        let mut pm_locked = PM.lock().unwrap();
+        pm_locked.as_mut().unwrap().add_plugin(&body.name, &path, desired, &body.depends_on)
+    }).await?;
+    Ok(json!({ "status": 1, "message": res, "library": stored}))
+}
+
+
+// Check the plugins and report their state to the center db every `duration` seconds. The plugin
+// manager is only locked, on a blocking thread, to take a snapshot, never during the request.
+pub async fn send(duration: u64) {
     loop {
         let now = Instant::now();
-        {
+        let snapshot = tokio::task::spawn_blocking(|| {
+            // failed plugins are stopped without holding the PM lock
+            let failed = {
+                let mut pm_locked = PM.lock().unwrap();
+                pm_locked.as_mut().unwrap().detach_failed()
+            };
+            let mut stopped = vec![];
+            for mut detached in failed {
+                let ret = detached.plugin.stop(detached.timeout);
+                stopped.push((detached, ret));
+            }
+
             let mut pm_locked = PM.lock().unwrap();
             let pm = pm_locked.as_mut().unwrap();
+            for (detached, ret) in stopped {
+                if let Err(e) = pm.finish_failed(detached, ret) {
+                    error!("{}", e);
+                }
+            }
             match pm.check_plugin() {
                 Ok(_) => {
                     debug!("plugins checked successfully");
@@ -171,25 +501,19 @@ pub async fn send(center_db_url: String, duration: u64) {
                     error!("plugins checked failed: {:?}", e);
                 },
             };
-            
-            match reqwest::Client::new()
-            .put(&center_db_url)
-            .json(&serde_json::json!(pm.plugin_cfg))
-            .send()
-            .await {
-                Ok(res) => {
-                    if res.status() != 200 {
-                        error!("send plugins status to center db failed, url:{}, reason {:?}", center_db_url, res);
-                    } else {
-                        debug!("send plugin state successfully");
-                    }
-                },
-                Err(e) => {
-                    error!("send plugins status to center db failed, url:{}, reason {:?}", center_db_url, e);
+            serde_json::to_value(&pm.plugin_cfg)
+        }).await;
+
+        // delivered in order over the uplink, reports queued during an outage are replayed
+        match snapshot {
+            Ok(Ok(state)) => {
+                if let Err(e) = uplink::submit(uplink::HOST_QUEUE, uplink::HOST_TOPIC, state) {
+                    error!("queue plugins status for center failed: {}", e);
                 }
-            };
+            },
+            Ok(Err(e)) => error!("serialize plugins status failed: {:?}", e),
+            Err(e) => error!("check plugins failed: {:?}", e),
         }
         tokio::time::sleep_until(now.checked_add(Duration::from_secs(duration)).unwrap()).await;
     }
-    
 }
@@ -0,0 +1,108 @@
+use std::convert::TryFrom;
+use std::fs;
+use log::{info, warn};
+use yaml_rust::Yaml;
+use ed25519_dalek::{PublicKey, Signature};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SigningMode {
+    // unsigned or tampered libraries are not loaded
+    Enforce,
+    // verification failures are logged, the library is loaded anyway, for development only
+    Permissive,
+    // no trusted keys configured, nothing is checked
+    Disabled,
+}
+
+impl SigningMode {
+    fn parse(mode: &str) -> Result<SigningMode, String> {
+        match mode {
+            "enforce" => Ok(SigningMode::Enforce),
+            "permissive" => Ok(SigningMode::Permissive),
+            _ => Err(format!("unknown signing mode: {}, expect enforce/permissive", mode)),
+        }
+    }
+}
+
+// Ed25519 keys trusted to sign plugin libraries, from the `signing` section of rsu.yaml.
+// A library at `path` ships with a detached signature at `<path>.sig`.
+#[derive(Debug)]
+pub struct SignaturePolicy {
+    mode: SigningMode,
+    keys: Vec<(String, PublicKey)>,
+}
+
+impl SignaturePolicy {
+    pub fn from_yaml(cfg: &Yaml) -> Result<SignaturePolicy, String> {
+        let mut keys: Vec<(String, PublicKey)> = vec![];
+        if let Some(trusted_keys) = cfg["trusted_keys"].as_vec() {
+            for key in trusted_keys {
+                let name = key["name"].as_str().ok_or("read signing key name failed")?;
+                let hex_key = key["key"].as_str().ok_or(format!("read signing key[{}] failed", name))?;
+                let bytes = decode_hex(hex_key).map_err(|e| format!("signing key[{}] is not hex: {}", name, e))?;
+                let public_key = PublicKey::from_bytes(&bytes).map_err(|e| format!("signing key[{}] is not an ed25519 public key: {}", name, e))?;
+                keys.push((String::from(name), public_key));
+            }
+        }
+
+        let mode = match cfg["mode"].as_str() {
+            Some(mode) => SigningMode::parse(mode)?,
+            None if keys.is_empty() => SigningMode::Disabled,
+            None => SigningMode::Enforce,
+        };
+        match mode {
+            SigningMode::Enforce if keys.is_empty() => return Err(String::from("signing mode is enforce, but no trusted_keys are configured")),
+            SigningMode::Permissive => warn!("plugin signatures are checked in permissive mode, unsigned libraries are loaded"),
+            SigningMode::Disabled => warn!("no trusted signing keys configured, plugin signatures are not checked"),
+            _ => {},
+        }
+        Ok(SignaturePolicy {mode, keys})
+    }
+
+    // Check `data` read from the library at `path` against its detached signature,
+    // returns the name of the key that signed it
+    pub fn verify(&self, path: &str, data: &[u8]) -> Result<Option<String>, String> {
+        if self.mode == SigningMode::Disabled {
+            return Ok(None)
+        }
+        match self.check(path, data) {
+            Ok(key) => {
+                info!("plugin library {} is signed by key[{}]", path, key);
+                Ok(Some(key))
+            },
+            Err(e) if self.mode == SigningMode::Permissive => {
+                warn!("plugin library {} failed signature check, loaded anyway in permissive mode: {}", path, e);
+                Ok(None)
+            },
+            Err(e) => Err(format!("plugin library {} is refused: {}", path, e)),
+        }
+    }
+
+    fn check(&self, path: &str, data: &[u8]) -> Result<String, String> {
+        let sig_path = format!("{}.sig", path);
+        let sig_bytes = fs::read(&sig_path).map_err(|e| format!("read signature {} failed: {:?}", sig_path, e))?;
+        // raw 64 bytes or hex text
+        let sig_bytes = if sig_bytes.len() == 64 {
+            sig_bytes
+        } else {
+            let text = String::from_utf8(sig_bytes).map_err(|_| format!("signature {} is neither raw nor hex", sig_path))?;
+            decode_hex(text.trim()).map_err(|e| format!("signature {} is not hex: {}", sig_path, e))?
+        };
+        let signature = Signature::try_from(&sig_bytes[..]).map_err(|e| format!("signature {} is invalid: {}", sig_path, e))?;
+
+        self.keys.iter()
+            .find(|(_, key)| key.verify_strict(data, &signature).is_ok())
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| String::from("signature does not match any trusted key, the library is unsigned or tampered"))
+    }
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    if text.len() % 2 != 0 {
+        return Err(String::from("odd number of digits"))
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2).unwrap_or("x"), 16).map_err(|e| e.to_string()))
+        .collect()
+}
@@ -0,0 +1,531 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use yaml_rust::Yaml;
+use lazy_static::lazy_static;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, PubAck, PubComp, QoS};
+use rsu_outbox::{Delivery, DeliveryFuture, Outbox, OutboxConfig, Transport};
+use rsu_plugin_abi::REPORT_OK;
+use crate::metrics;
+use crate::identity::Identity;
+
+
+// queue of the plugin state reports of the rsu itself
+pub const HOST_QUEUE: &str = "rsu";
+// topic of the plugin state reports, `center_db_url` of older configs ends with it
+pub const HOST_TOPIC: &str = "plugins/status/";
+// topic of the registration report queued at startup, ahead of the first plugin state report
+pub const REGISTER_TOPIC: &str = "register/";
+// reports a plugin-host child keeps until the parent collects them
+const RELAY_LIMIT: usize = 4096;
+const MQTT_KEEP_ALIVE_SECS: u16 = 30;
+
+// How reports reach the center, the `uplink` section of rsu.yaml
+#[derive(Debug, Clone)]
+pub enum TransportCfg {
+    // PUT of the json body to `url` + topic
+    Http { url: String, timeout: Duration },
+    // zenoh put of the json body to `prefix`/topic
+    Zenoh { prefix: String, mode: Option<String>, peers: Vec<String> },
+    // MQTT publish of the json body to `topic_prefix`/topic
+    Mqtt { host: String, port: u16, client_id: String, topic_prefix: String, qos: QoS,
+           username: Option<String>, password: Option<String>, timeout: Duration },
+}
+
+impl TransportCfg {
+    // `center_db_url` is the HTTP url of the host report, `timeout` bounds a single delivery.
+    // zenoh and mqtt default to the rsu_id of `identity` in their prefix.
+    pub fn from_yaml(cfg: &Yaml, center_db_url: &str, identity: &Identity, timeout: Duration) -> Result<TransportCfg, String> {
+        match cfg["transport"].as_str().unwrap_or("http") {
+            "http" => {
+                let url = match cfg["http"]["url"].as_str() {
+                    Some(url) => String::from(url),
+                    // the old host report url is the base url followed by the host topic
+                    None => center_db_url.strip_suffix(HOST_TOPIC).map(String::from).ok_or(format!(
+                        "uplink.http.url is not configured and center_db_url {} does not end with {}", center_db_url, HOST_TOPIC))?,
+                };
+                if url.is_empty() {
+                    return Err(String::from("uplink.http.url is empty"))
+                }
+                Ok(TransportCfg::Http {url, timeout})
+            },
+            "zenoh" => {
+                let zenoh = &cfg["zenoh"];
+                let prefix = zenoh["prefix"].as_str().map(String::from).unwrap_or(format!("/rsu/{}", identity.rsu_id));
+                if !prefix.starts_with('/') {
+                    return Err(format!("uplink.zenoh.prefix {} must start with /", prefix))
+                }
+                let mut peers: Vec<String> = vec![];
+                if let Some(list) = zenoh["peers"].as_vec() {
+                    for peer in list {
+                        peers.push(String::from(peer.as_str().ok_or("read uplink.zenoh.peers failed")?));
+                    }
+                }
+                Ok(TransportCfg::Zenoh {
+                    prefix: String::from(prefix.trim_end_matches('/')),
+                    mode: zenoh["mode"].as_str().map(String::from),
+                    peers,
+                })
+            },
+            "mqtt" => {
+                let mqtt = &cfg["mqtt"];
+                let qos = match mqtt["qos"].as_i64().unwrap_or(1) {
+                    0 => QoS::AtMostOnce,
+                    1 => QoS::AtLeastOnce,
+                    2 => QoS::ExactlyOnce,
+                    qos => return Err(format!("unknown uplink.mqtt.qos: {}, expect 0/1/2", qos)),
+                };
+                Ok(TransportCfg::Mqtt {
+                    host: String::from(mqtt["host"].as_str().unwrap_or("127.0.0.1")),
+                    port: mqtt["port"].as_i64().unwrap_or(1883) as u16,
+                    client_id: mqtt["client_id"].as_str().map(String::from).unwrap_or(format!("rsu-{}", identity.rsu_id)),
+                    topic_prefix: mqtt["topic_prefix"].as_str().map(String::from)
+                                    .unwrap_or(format!("rsu/{}", identity.rsu_id)).trim_end_matches('/').to_string(),
+                    qos,
+                    username: mqtt["username"].as_str().map(String::from),
+                    password: mqtt["password"].as_str().map(String::from),
+                    timeout,
+                })
+            },
+            transport => Err(format!("unknown uplink transport: {}, expect http/zenoh/mqtt", transport)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TransportCfg::Http {..} => "http",
+            TransportCfg::Zenoh {..} => "zenoh",
+            TransportCfg::Mqtt {..} => "mqtt",
+        }
+    }
+}
+
+enum Uplink {
+    Http(HttpUplink),
+    Zenoh(ZenohUplink),
+    Mqtt(MqttUplink),
+}
+
+impl Uplink {
+    async fn connect(cfg: TransportCfg) -> Result<Uplink, String> {
+        match cfg {
+            TransportCfg::Http {url, timeout} => {
+                let client = reqwest::Client::builder()
+                    .connect_timeout(timeout)
+                    .timeout(timeout)
+                    .build()
+                    .map_err(|e| format!("create uplink http client failed: {:?}", e))?;
+                Ok(Uplink::Http(HttpUplink {url, client}))
+            },
+            TransportCfg::Zenoh {prefix, mode, peers} => {
+                let mut config = zenoh::Properties::default();
+                if let Some(mode) = mode {
+                    config.insert(String::from("mode"), mode);
+                }
+                if !peers.is_empty() {
+                    config.insert(String::from("peer"), peers.join(","));
+                }
+                let zenoh = zenoh::Zenoh::new(config.into()).await.map_err(|e| format!("open uplink zenoh session failed: {:?}", e))?;
+                Ok(Uplink::Zenoh(ZenohUplink {prefix, zenoh}))
+            },
+            TransportCfg::Mqtt {host, port, client_id, topic_prefix, qos, username, password, timeout} => {
+                let mut options = MqttOptions::new(client_id, host, port);
+                options.set_keep_alive(MQTT_KEEP_ALIVE_SECS);
+                if let (Some(username), Some(password)) = (username, password) {
+                    options.set_credentials(username, password);
+                }
+                let session = Arc::new(Mutex::new(MqttSession::default()));
+                tokio::spawn(run_mqtt_session(options, qos, Arc::clone(&session)));
+                Ok(Uplink::Mqtt(MqttUplink {topic_prefix, qos, session, sending: tokio::sync::Mutex::new(()), timeout}))
+            },
+        }
+    }
+}
+
+impl Transport for Uplink {
+    fn deliver<'a>(&'a self, topic: &'a str, body: &'a Value) -> DeliveryFuture<'a> {
+        match self {
+            Uplink::Http(u) => Box::pin(u.deliver(topic, body)),
+            Uplink::Zenoh(u) => Box::pin(u.deliver(topic, body)),
+            Uplink::Mqtt(u) => Box::pin(u.deliver(topic, body)),
+        }
+    }
+}
+
+struct HttpUplink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpUplink {
+    async fn deliver(&self, topic: &str, body: &Value) -> Delivery {
+        // entries queued before the uplink carry the full url
+        let url = if topic.starts_with("http://") || topic.starts_with("https://") {
+            String::from(topic)
+        } else {
+            format!("{}{}", self.url, topic)
+        };
+        match self.client.put(&url).json(body).send().await {
+            Ok(res) => {
+                let status = res.status();
+                if status.is_success() {
+                    Delivery::Delivered
+                } else if status.is_client_error() && status.as_u16() != 408 && status.as_u16() != 429 {
+                    Delivery::Rejected(format!("{} answered {}", url, status))
+                } else {
+                    Delivery::Retry(format!("{} answered {}", url, status))
+                }
+            },
+            Err(e) => Delivery::Retry(format!("{:?}", e)),
+        }
+    }
+}
+
+// A put is delivered once the session accepted it, zenoh does not acknowledge puts
+struct ZenohUplink {
+    prefix: String,
+    zenoh: zenoh::Zenoh,
+}
+
+impl ZenohUplink {
+    async fn deliver(&self, topic: &str, body: &Value) -> Delivery {
+        let path = match key_of(&self.prefix, topic).and_then(|p| zenoh::Path::try_from(p).map_err(|e| format!("{:?}", e))) {
+            Ok(path) => path,
+            Err(e) => return Delivery::Rejected(format!("invalid zenoh path for {}: {}", topic, e)),
+        };
+        let workspace = match self.zenoh.workspace(None).await {
+            Ok(workspace) => workspace,
+            Err(e) => return Delivery::Retry(format!("open zenoh workspace failed: {:?}", e)),
+        };
+        match workspace.put(&path, zenoh::Value::Json(body.to_string())).await {
+            Ok(_) => Delivery::Delivered,
+            Err(e) => Delivery::Retry(format!("zenoh put {} failed: {:?}", path, e)),
+        }
+    }
+}
+
+// A QoS 1/2 publish is delivered once the broker acknowledged it, a QoS 0 publish once it is
+// written to the connection. Publishes go out one at a time, so the next acknowledgement of the
+// session is the one of the pending publish.
+struct MqttUplink {
+    topic_prefix: String,
+    qos: QoS,
+    session: Arc<Mutex<MqttSession>>,
+    sending: tokio::sync::Mutex<()>,
+    timeout: Duration,
+}
+
+#[derive(Default)]
+struct MqttSession {
+    // set while the broker is connected
+    client: Option<AsyncClient>,
+    pending: Option<PendingPublish>,
+}
+
+struct PendingPublish {
+    // packet id assigned by the client once the publish is written
+    pkid: Option<u16>,
+    done: oneshot::Sender<Result<(), String>>,
+}
+
+impl MqttSession {
+    fn finish(&mut self, ret: Result<(), String>) {
+        if let Some(pending) = self.pending.take() {
+            let _ = pending.done.send(ret);
+        }
+    }
+}
+
+impl MqttUplink {
+    async fn deliver(&self, topic: &str, body: &Value) -> Delivery {
+        let topic = match key_of(&self.topic_prefix, topic) {
+            Ok(topic) => topic,
+            Err(e) => return Delivery::Rejected(e),
+        };
+        let _sending = self.sending.lock().await;
+        let (done, acked) = oneshot::channel();
+        let client = {
+            let mut session = self.session.lock().unwrap();
+            let client = match &session.client {
+                Some(client) => client.clone(),
+                None => return Delivery::Retry(String::from("mqtt broker is not connected")),
+            };
+            session.pending = Some(PendingPublish {pkid: None, done});
+            client
+        };
+        let sent = tokio::time::timeout(self.timeout, async {
+            client.publish(topic.clone(), self.qos, false, body.to_string()).await.map_err(|e| format!("{:?}", e))?;
+            acked.await.map_err(|_| String::from("connection closed"))?
+        }).await;
+        match sent {
+            Ok(Ok(_)) => Delivery::Delivered,
+            Ok(Err(e)) => Delivery::Retry(format!("mqtt publish {} failed: {}", topic, e)),
+            Err(_) => {
+                // a late acknowledgement would be taken for the next publish, start a new session
+                self.session.lock().unwrap().client = None;
+                let _ = client.cancel().await;
+                Delivery::Retry(format!("mqtt publish {} was not acknowledged within {:?}", topic, self.timeout))
+            },
+        }
+    }
+}
+
+// Poll a client session until it fails, then connect a new one. Publishes the old session did not
+// get acknowledged fail, the outbox sends them again over the new session.
+async fn run_mqtt_session(options: MqttOptions, qos: QoS, session: Arc<Mutex<MqttSession>>) {
+    loop {
+        let (client, mut eventloop) = AsyncClient::new(options.clone(), 16);
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("uplink mqtt broker connected");
+                    session.lock().unwrap().client = Some(client.clone());
+                },
+                Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                    let mut session = session.lock().unwrap();
+                    if qos == QoS::AtMostOnce {
+                        session.finish(Ok(()));
+                    } else if let Some(pending) = session.pending.as_mut() {
+                        pending.pkid = Some(pkid);
+                    }
+                },
+                Ok(Event::Incoming(Packet::PubAck(PubAck {pkid, ..}))) | Ok(Event::Incoming(Packet::PubComp(PubComp {pkid, ..}))) => {
+                    let mut session = session.lock().unwrap();
+                    if session.pending.as_ref().map_or(false, |p| p.pkid == Some(pkid)) {
+                        session.finish(Ok(()));
+                    }
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    let mut session = session.lock().unwrap();
+                    if session.client.take().is_some() {
+                        warn!("uplink mqtt broker disconnected: {:?}", e);
+                    }
+                    session.finish(Err(format!("connection lost: {:?}", e)));
+                    break
+                },
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// `prefix`/`topic` without the trailing slash the HTTP topics have
+fn key_of(prefix: &str, topic: &str) -> Result<String, String> {
+    if topic.contains("://") {
+        return Err(format!("{} is a url, not a topic", topic))
+    }
+    let topic = topic.trim_matches('/');
+    if topic.is_empty() {
+        return Err(String::from("empty topic"))
+    }
+    Ok(format!("{}/{}", prefix, topic))
+}
+
+// Queues of every report source, each drained over the shared uplink
+struct Router {
+    uplink: Arc<Uplink>,
+    outbox: OutboxConfig,
+    queues: Mutex<HashMap<String, Outbox>>,
+    // plugins report from their own threads and runtimes, drain tasks run on the host runtime
+    runtime: Handle,
+}
+
+impl Router {
+    fn submit(&self, source: &str, topic: &str, body: &Value) -> Result<(), String> {
+        let outbox = {
+            let mut queues = self.queues.lock().map_err(|e| format!("lock uplink queues failed: {:?}", e))?;
+            match queues.get(source) {
+                Some(outbox) => outbox.clone(),
+                None => {
+                    let outbox = Outbox::open(source, self.outbox.clone())?;
+                    self.spawn_drain(outbox.clone());
+                    queues.insert(String::from(source), outbox.clone());
+                    outbox
+                }
+            }
+        };
+        outbox.push(topic, body)
+    }
+
+    fn spawn_drain(&self, outbox: Outbox) {
+        let uplink = Arc::clone(&self.uplink);
+        self.runtime.spawn(async move {
+            let host = outbox.name() == HOST_QUEUE;
+            outbox.run(&*uplink, std::future::pending(), |topic, delivery, elapsed| if host {
+                metrics::report_sent(matches!(delivery, Delivery::Delivered), elapsed);
+                if topic == REGISTER_TOPIC {
+                    registration_answered(delivery);
+                }
+            }).await
+        });
+    }
+}
+
+// The center answered the registration, over http it accepted it with a 2xx status
+fn registration_answered(delivery: &Delivery) {
+    match delivery {
+        Delivery::Delivered => {
+            info!("registration delivered to the center");
+            metrics::set_registered(true);
+        },
+        Delivery::Rejected(reason) => error!("center refused the registration: {}", reason),
+        Delivery::Retry(_) => {},
+    }
+}
+
+// A report a plugin-host child hands to its parent
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Report {
+    pub source: String,
+    pub topic: String,
+    pub body: Value,
+}
+
+enum Sink {
+    Unset,
+    Direct(Arc<Router>),
+    // in a plugin-host child, reports are collected by the parent
+    Relay(VecDeque<Report>),
+}
+
+lazy_static! {
+    static ref SINK: Mutex<Sink> = Mutex::new(Sink::Unset);
+}
+
+// Connect the uplink and start draining the queues left by a previous run,
+// has to be called inside the host runtime
+pub async fn init(cfg: TransportCfg, outbox: OutboxConfig) -> Result<(), String> {
+    info!("reports are delivered over {}", cfg.name());
+    let uplink = Arc::new(Uplink::connect(cfg).await?);
+    let router = Router {uplink, outbox, queues: Mutex::new(HashMap::new()), runtime: Handle::current()};
+    for queue in rsu_outbox::queue_stats(&router.outbox.dir) {
+        if queue.depth > 0 {
+            let outbox = Outbox::open(&queue.name, router.outbox.clone())?;
+            router.spawn_drain(outbox.clone());
+            router.queues.lock().unwrap().insert(queue.name, outbox);
+        }
+    }
+    *SINK.lock().unwrap() = Sink::Direct(Arc::new(router));
+    Ok(())
+}
+
+// Keep reports of this process for the parent rsu, see `take_relayed`
+pub fn relay() {
+    *SINK.lock().unwrap() = Sink::Relay(VecDeque::new());
+}
+
+// Queue `body` from `source` for delivery under `topic`
+pub fn submit(source: &str, topic: &str, body: Value) -> Result<(), String> {
+    let router = {
+        let mut sink = SINK.lock().map_err(|e| format!("lock uplink failed: {:?}", e))?;
+        match &mut *sink {
+            Sink::Direct(router) => Arc::clone(router),
+            Sink::Relay(reports) => {
+                if reports.len() == RELAY_LIMIT {
+                    reports.pop_front();
+                    error!("relayed reports are not collected, dropped the oldest of {}", source);
+                }
+                reports.push_back(Report {source: String::from(source), topic: String::from(topic), body});
+                return Ok(())
+            },
+            Sink::Unset => return Err(String::from("uplink is not configured")),
+        }
+    };
+    router.submit(source, topic, &body)
+}
+
+// Reports kept since the last call, empty unless `relay` was called
+pub fn take_relayed() -> Vec<Report> {
+    match &mut *SINK.lock().unwrap() {
+        Sink::Relay(reports) => reports.drain(..).collect(),
+        _ => vec![],
+    }
+}
+
+// `report` of the `HostContext` handed to in-process plugins, `host_data` points to the plugin name
+pub unsafe extern "C" fn report_from_plugin(host_data: *const c_void, topic: *const u8, topic_len: usize,
+                                            payload: *const u8, payload_len: usize) -> i32 {
+    if host_data.is_null() || topic.is_null() || payload.is_null() {
+        return -1
+    }
+    let source = &*(host_data as *const String);
+    let topic = match std::str::from_utf8(std::slice::from_raw_parts(topic, topic_len)) {
+        Ok(topic) => topic,
+        Err(_) => {
+            error!("plugin[{}] report topic is not utf-8", source);
+            return -1
+        }
+    };
+    let body: Value = match serde_json::from_slice(std::slice::from_raw_parts(payload, payload_len)) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("plugin[{}] report for {} is not json: {:?}", source, topic, e);
+            return -1
+        }
+    };
+    match submit(source, topic, body) {
+        Ok(_) => REPORT_OK,
+        Err(e) => {
+            error!("plugin[{}] report for {} failed: {}", source, topic, e);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mqtt_cfg(port: u16) -> TransportCfg {
+        TransportCfg::Mqtt {
+            host: String::from("127.0.0.1"),
+            port,
+            client_id: format!("rsu-test-{}", std::process::id()),
+            topic_prefix: String::from("rsu/test"),
+            qos: QoS::AtLeastOnce,
+            username: None,
+            password: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn key_of_joins_prefix_and_topic() {
+        assert_eq!(key_of("/rsu/7", "plugins/status/").unwrap(), "/rsu/7/plugins/status");
+        assert_eq!(key_of("rsu/7", "/vehicle/status").unwrap(), "rsu/7/vehicle/status");
+        assert!(key_of("rsu/7", "/").is_err());
+        assert!(key_of("rsu/7", "http://center/plugins/status/").is_err());
+    }
+
+    #[tokio::test]
+    async fn mqtt_without_a_broker_is_retried() {
+        // nothing listens on port 1 of the loopback
+        let uplink = Uplink::connect(mqtt_cfg(1)).await.unwrap();
+        match uplink.deliver("plugins/status/", &Value::Null).await {
+            Delivery::Retry(_) => {},
+            _ => panic!("delivered without a broker"),
+        }
+    }
+
+    // needs a broker on 127.0.0.1:1883, e.g. `mosquitto -p 1883`
+    #[tokio::test]
+    #[ignore]
+    async fn mqtt_is_delivered_once_the_broker_acknowledged() {
+        let uplink = Uplink::connect(mqtt_cfg(1883)).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..3 {
+            match uplink.deliver("plugins/status/", &serde_json::json!({"ok": true})).await {
+                Delivery::Delivered => {},
+                Delivery::Retry(e) | Delivery::Rejected(e) => panic!("publish failed: {}", e),
+            }
+        }
+    }
+}
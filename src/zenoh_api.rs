@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use async_std::stream::StreamExt;
+use log::{info, warn, error};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use yaml_rust::Yaml;
+use zenoh::{GetRequest, GetRequestStream, Path, PathExpr, Workspace, Zenoh};
+use rsu_plugin_abi::RSU_PLUGIN_ABI_VERSION;
+use crate::api::{ActivateRequest, ApiError, ErrorCode};
+use crate::auth::{Auth, Role};
+use crate::events::{self, EventKind};
+use crate::identity::Identity;
+use crate::plugin::unix_now;
+use crate::server::{self, PM};
+
+// how far, in seconds, the `ts` of a signed query may be off the rsu clock
+const SIGNATURE_WINDOW: u64 = 30;
+
+
+// The `zenoh_api` section of rsu.yaml, the plugin API served as zenoh evals under `prefix`
+#[derive(Debug, Clone)]
+pub struct ZenohApiCfg {
+    pub prefix: String,
+    pub mode: Option<String>,
+    pub peers: Vec<String>,
+}
+
+impl ZenohApiCfg {
+    // None when rsu.yaml has no `zenoh_api` section, `prefix` defaults to /rsu/<rsu_id>
+    pub fn from_yaml(cfg: &Yaml, identity: &Identity) -> Result<Option<ZenohApiCfg>, String> {
+        if cfg.is_badvalue() || cfg.is_null() {
+            return Ok(None)
+        }
+        let prefix = cfg["prefix"].as_str().map(String::from).unwrap_or(format!("/rsu/{}", identity.rsu_id));
+        if !prefix.starts_with('/') {
+            return Err(format!("zenoh_api.prefix {} must start with /", prefix))
+        }
+        let mut peers: Vec<String> = vec![];
+        if let Some(list) = cfg["peers"].as_vec() {
+            for peer in list {
+                peers.push(String::from(peer.as_str().ok_or("read zenoh_api.peers failed")?));
+            }
+        }
+        Ok(Some(ZenohApiCfg {
+            prefix: String::from(prefix.trim_end_matches('/')),
+            mode: cfg["mode"].as_str().map(String::from),
+            peers,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Query {
+    Alive,
+    List,
+    Plugin,
+    Start,
+    Stop,
+}
+
+struct ZenohApi {
+    prefix: String,
+    auth: Auth,
+    rsu_id: String,
+    started_at: u64,
+    // nonces of the signed queries accepted within the signature window, with their `ts`
+    nonces: Mutex<HashMap<String, u64>>,
+}
+
+// Serve the evals and publish the plugin state changes until the session is closed
+pub async fn run(cfg: ZenohApiCfg, auth: Auth, identity: Identity) -> Result<(), String> {
+    let mut config = zenoh::Properties::default();
+    if let Some(mode) = cfg.mode {
+        config.insert(String::from("mode"), mode);
+    }
+    if !cfg.peers.is_empty() {
+        config.insert(String::from("peer"), cfg.peers.join(","));
+    }
+    let zenoh = Zenoh::new(config.into()).await.map_err(|e| format!("open zenoh api session failed: {:?}", e))?;
+    let workspace = zenoh.workspace(None).await.map_err(|e| format!("open zenoh api workspace failed: {:?}", e))?;
+
+    let api = Arc::new(ZenohApi {
+        prefix: cfg.prefix,
+        auth,
+        rsu_id: identity.rsu_id,
+        started_at: unix_now(),
+        nonces: Mutex::new(HashMap::new()),
+    });
+    // a get on <prefix>/alive is answered while the session is up, it is the liveliness token of the rsu
+    let mut alive = register(&workspace, format!("{}/alive", api.prefix)).await?;
+    let mut list = register(&workspace, format!("{}/plugins", api.prefix)).await?;
+    let mut plugin = register(&workspace, format!("{}/plugins/*", api.prefix)).await?;
+    let mut start = register(&workspace, format!("{}/plugins/*/start", api.prefix)).await?;
+    let mut stop = register(&workspace, format!("{}/plugins/*/stop", api.prefix)).await?;
+    let (_, mut receiver) = events::subscribe(None);
+    info!("zenoh api serving {}", api.prefix);
+
+    loop {
+        let (query, req) = tokio::select! {
+            Some(req) = alive.next() => (Query::Alive, req),
+            Some(req) = list.next() => (Query::List, req),
+            Some(req) = plugin.next() => (Query::Plugin, req),
+            Some(req) = start.next() => (Query::Start, req),
+            Some(req) = stop.next() => (Query::Stop, req),
+            event = receiver.recv() => {
+                match event {
+                    Ok(event) => publish(&workspace, &api.prefix, event.kind).await,
+                    Err(RecvError::Lagged(missed)) => warn!("zenoh api missed {} plugin events", missed),
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+                continue;
+            },
+            else => return Ok(()),
+        };
+        // stopping a plugin may take a while, the other queries are answered meanwhile
+        tokio::spawn(Arc::clone(&api).handle(query, req));
+    }
+}
+
+async fn register<'a>(workspace: &'a Workspace<'a>, path: String) -> Result<GetRequestStream<'a>, String> {
+    let path_expr = PathExpr::try_from(path.clone()).map_err(|e| format!("invalid zenoh path {}: {:?}", path, e))?;
+    workspace.register_eval(&path_expr).await.map_err(|e| format!("register zenoh eval {} failed: {:?}", path, e))
+}
+
+// Plugin state changes are put to <prefix>/plugins/<plugin>/state, the value is the `state` event
+async fn publish(workspace: &Workspace<'_>, prefix: &str, kind: EventKind) {
+    let plugin = match &kind {
+        EventKind::State {plugin, ..} => plugin.clone(),
+        _ => return,
+    };
+    let path = format!("{}/plugins/{}/state", prefix, plugin);
+    let value = match serde_json::to_string(&kind) {
+        Ok(value) => value,
+        Err(e) => {
+            error!("encode zenoh api event failed: {:?}", e);
+            return
+        }
+    };
+    match Path::try_from(path.clone()) {
+        Ok(path) => if let Err(e) = workspace.put(&path, zenoh::Value::Json(value)).await {
+            warn!("zenoh api put {} failed: {:?}", path, e);
+        },
+        Err(e) => error!("invalid zenoh path {}: {:?}", path, e),
+    }
+}
+
+impl ZenohApi {
+    async fn handle(self: Arc<Self>, query: Query, req: GetRequest) {
+        let selector = req.selector.path_expr.to_string();
+        match query {
+            Query::Alive => {
+                let body = json!({
+                    "rsu_id": self.rsu_id,
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "plugin_abi": RSU_PLUGIN_ABI_VERSION,
+                    "started_at": self.started_at,
+                });
+                reply(&req, format!("{}/alive", self.prefix), body).await
+            },
+            Query::List => {
+                let ret = match self.authorize(&req, Role::Viewer) {
+                    Ok(_) => blocking(|| {
+                        let pm_locked = PM.lock().unwrap();
+                        Ok(json!({ "status": 1, "plugins": pm_locked.as_ref().unwrap().plugin_views()}))
+                    }).await,
+                    Err(e) => Err(e),
+                };
+                reply(&req, format!("{}/plugins", self.prefix), respond(ret)).await
+            },
+            // a wildcard query, e.g. <prefix>/plugins/*, gets a reply per matching plugin
+            Query::Plugin => {
+                if let Err(e) = self.authorize(&req, Role::Viewer) {
+                    if let Some(path) = concrete(&selector) {
+                        reply(&req, path, e.to_json()).await;
+                    }
+                    return
+                }
+                let views = blocking(|| {
+                    let pm_locked = PM.lock().unwrap();
+                    let pm = pm_locked.as_ref().unwrap();
+                    Ok(pm.plugin_views().into_iter().filter_map(|v| pm.plugin_view(&v.name, true)).collect::<Vec<_>>())
+                }).await;
+                let views = match views {
+                    Ok(views) => views,
+                    Err(e) => {
+                        if let Some(path) = concrete(&selector) {
+                            reply(&req, path, e.to_json()).await;
+                        }
+                        return
+                    }
+                };
+                for view in views {
+                    let path = format!("{}/plugins/{}", self.prefix, view.name);
+                    if Path::try_from(path.clone()).map(|p| req.selector.path_expr.matches(&p)).unwrap_or(false) {
+                        reply(&req, path, json!({ "status": 1, "plugin": view})).await;
+                    }
+                }
+            },
+            // commands are only run for a single plugin, not for a wildcard
+            Query::Start | Query::Stop => {
+                let path = match concrete(&selector) {
+                    Some(path) => path,
+                    None => {
+                        warn!("zenoh api ignored {}, commands need a plugin name", selector);
+                        return
+                    }
+                };
+                let name = path.trim_start_matches(&format!("{}/plugins/", self.prefix))
+                               .rsplitn(2, '/').nth(1).map(String::from).unwrap_or_default();
+                let active = matches!(query, Query::Start);
+                let ret = match self.authorize(&req, Role::Operator) {
+                    Ok(_) => blocking(move || server::activate(ActivateRequest {name, active})).await,
+                    Err(e) => Err(e),
+                };
+                let (ok, message) = match &ret {
+                    Ok(body) => (true, String::from(body["message"].as_str().unwrap_or_default())),
+                    Err(e) => (false, e.message.clone()),
+                };
+                events::publish(EventKind::Api {action: path.clone(), remote: Some(String::from("zenoh")), ok, message});
+                reply(&req, path, respond(ret)).await
+            },
+        }
+    }
+
+    // Queries are seen by every peer routing them, so they carry no token but its name `key`, a `ts`,
+    // a random `nonce` and `sig`, the HMAC of "<path>\n<ts>\n<nonce>" keyed with the token.
+    // A signature is accepted once, and only within SIGNATURE_WINDOW of the rsu clock.
+    fn authorize(&self, req: &GetRequest, role: Role) -> Result<Role, ApiError> {
+        let props = &req.selector.properties;
+        let prop = |key: &str| props.get(key).map(String::as_str).filter(|v| !v.is_empty());
+        let (ts, nonce) = match (prop("ts").and_then(|ts| ts.parse::<u64>().ok()), prop("nonce")) {
+            (Some(ts), Some(nonce)) => (ts, nonce),
+            _ => return Err(ApiError::new(ErrorCode::Unauthorized, String::from("authentication required, expect key, ts, nonce and sig"))),
+        };
+        let message = format!("{}\n{}\n{}", req.selector.path_expr, ts, nonce);
+        let granted = self.auth.require_signature(prop("key"), &message, prop("sig"), role)?;
+
+        let now = unix_now();
+        if ts + SIGNATURE_WINDOW < now || ts > now + SIGNATURE_WINDOW {
+            return Err(ApiError::new(ErrorCode::Unauthorized, format!("ts {} is more than {}s off the rsu clock", ts, SIGNATURE_WINDOW)))
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        // older entries can not be replayed anymore, their ts is out of the window
+        nonces.retain(|_, seen| *seen + SIGNATURE_WINDOW >= now);
+        if nonces.insert(String::from(nonce), ts).is_some() {
+            return Err(ApiError::new(ErrorCode::Unauthorized, String::from("nonce has already been used")))
+        }
+        Ok(granted)
+    }
+}
+
+// the handlers lock the plugin manager, which must not block a tokio worker
+async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+    where F: FnOnce() -> Result<T, ApiError> + Send + 'static, T: Send + 'static {
+    tokio::task::spawn_blocking(f).await
+        .unwrap_or_else(|e| Err(ApiError::new(ErrorCode::Internal, format!("zenoh api request panicked: {:?}", e))))
+}
+
+fn respond(ret: Result<Value, ApiError>) -> Value {
+    ret.unwrap_or_else(|e| e.to_json())
+}
+
+// a reply needs a path, queries with wildcards can only be answered per plugin
+fn concrete(selector: &str) -> Option<String> {
+    if selector.contains('*') {
+        None
+    } else {
+        Some(String::from(selector))
+    }
+}
+
+async fn reply(req: &GetRequest, path: String, body: Value) {
+    match Path::try_from(path.clone()) {
+        Ok(path) => req.reply(path, zenoh::Value::Json(body.to_string())).await,
+        Err(e) => error!("invalid zenoh path {}: {:?}", path, e),
+    }
+}